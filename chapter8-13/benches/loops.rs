@@ -0,0 +1,51 @@
+// Benchmarks of loop heavy Lox programs
+// Each program is run by both the tree walking interpreter and the virtual
+// machine. The loops spend their time looking up variables and comparing
+// strings, so they show how much names and strings cost.
+//
+//   cargo bench --bench loops
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::eval::OutputBuffer;
+use rlox::interpreter::Interpreter;
+use rlox::vm::Vm;
+use std::fs;
+use std::path::Path;
+
+const COUNT: &str = "
+var sum = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  sum = sum + i;
+}
+print sum;
+";
+
+const STRINGS: &str = "
+var name = \"lox\";
+var matches = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  if (name == \"lox\") matches = matches + 1;
+}
+print matches;
+";
+
+fn bench_program(c: &mut Criterion, name: &str, source: &str) {
+    let stmts = Interpreter::compile(source).unwrap();
+    c.bench_function(&format!("{} tree", name), |b| {
+        let mut interpreter = Interpreter::with_output(Box::new(OutputBuffer::new()));
+        b.iter(|| interpreter.execute(&stmts).unwrap())
+    });
+    c.bench_function(&format!("{} vm", name), |b| {
+        let mut vm = Vm::with_output(Box::new(OutputBuffer::new()));
+        b.iter(|| vm.interpret(&stmts).unwrap())
+    });
+}
+
+fn loops(c: &mut Criterion) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("samples/for.lox");
+    bench_program(c, "for.lox", &fs::read_to_string(path).unwrap());
+    bench_program(c, "count", COUNT);
+    bench_program(c, "strings", STRINGS);
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+// Benchmark of the scanner on generated Lox files of growing size
+// Scanning should take time in proportion to the size of the file, which shows
+// up as the same throughput for every size.
+//
+//   cargo bench --bench scan
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rlox::scan::scan;
+
+// A bit of everything the scanner handles, including text that isn't ASCII
+const BLOCK: &str = "
+// Count down from n
+fun countdown(n) {
+  while (n >= 0) {
+    print \"n is now \" + \"caf\u{e9}\";
+    n = n - 1.5;
+  }
+  return n != nil and !false or true;
+}
+";
+
+// Source of at least `size` bytes
+fn generate(size: usize) -> String {
+    BLOCK.repeat(size.div_ceil(BLOCK.len()))
+}
+
+fn scanning(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    for size in [64 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024] {
+        let source = generate(size);
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &source, |b, source| {
+            b.iter(|| scan(source).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scanning);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+// Bytecode for the virtual machine
+// A Chunk is the compiled code of one function: its instructions, the span of
+// source each instruction came from, for runtime errors, and its constants.
+use crate::object::Value;
+use crate::scan::Span;
+
+/// An instruction. Operands are indexes into the constant table (for constants
+/// and names), stack slots, upvalue indexes, argument counts or jump distances.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    Constant(u8),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(u8),
+    SetLocal(u8),
+    GetGlobal(u8),
+    DefineGlobal(u8),
+    SetGlobal(u8),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    GetProperty(u8),
+    SetProperty(u8),
+    GetSuper(u8),
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    // Pops the given number of values and pushes them joined as a string
+    Interpolate(u8),
+    // Jumps are forwards from the next instruction, loops backwards from it
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    Call(u8),
+    Closure(u8),
+    CloseUpvalue,
+    Return,
+    Class(u8),
+    Inherit,
+    Method(u8),
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<OpCode>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// write adds an instruction and returns where it is in the code
+    pub fn write(&mut self, op: OpCode, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+    /// add_constant adds a value to the constant table and returns its index
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
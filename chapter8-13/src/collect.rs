@@ -0,0 +1,384 @@
+// Cycle collector for the tree walking interpreter
+// Values are reference counted, which frees most of them as soon as they are
+// no longer used, but not a group of them that refer to each other, such as a
+// function and the environment it was declared in. Every instance, and every
+// environment that a function closes over, is tracked, and once enough have
+// been tracked since the last time, those that are only referred to from within
+// the tracked objects are found and emptied, which breaks their cycles so that
+// reference counting frees them. Every cycle goes through one of them, so the
+// blocks and calls of a program that declare no functions are never tracked.
+//
+// There are no roots to start from, the interpreter's are variables on the Rust
+// stack, so this works the other way round to the virtual machine's collector.
+// For each object it counts how many references to it come from other objects:
+// an object with more references than that is referred to from somewhere else
+// and, along with everything it refers to, is live.
+use crate::eval::{EvalState, LoxClass, LoxFunction, LoxInstance};
+use crate::parse::Value;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+// Collect once this many environments and instances are tracked. What they
+// hold varies too much for their size to mean anything, so they are counted.
+const FIRST_GC: usize = 10_000;
+
+// Environments and instances are the only objects that change after they are
+// made, so every cycle goes through one of them, and the only reference from a
+// value to an environment is a function's closure
+enum Tracked {
+    Environment(Weak<RefCell<EvalState>>),
+    Instance(Weak<LoxInstance>),
+}
+
+impl Tracked {
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Environment(environment) => environment.strong_count() > 0,
+            Tracked::Instance(instance) => instance.strong_count() > 0,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Tracked::Environment(environment) => environment.upgrade().map(Node::Environment),
+            Tracked::Instance(instance) => instance.upgrade().map(Node::Instance),
+        }
+    }
+}
+
+/// How much a cycle collector has done. Objects are counted rather than
+/// their bytes, unlike the virtual machine's `GcStats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CycleStats {
+    pub collections: usize,
+    pub objects_tracked: usize,
+    pub objects_freed: usize,
+}
+
+impl fmt::Display for CycleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} collections, {} objects tracked, {} objects freed",
+            self.collections, self.objects_tracked, self.objects_freed
+        )
+    }
+}
+
+/// The cycle collector of one interpreter, which its globals own. It is
+/// changed while the program runs, through a shared reference to the globals,
+/// so its state lives in a RefCell.
+#[derive(Default)]
+pub struct Collector(RefCell<CollectorState>);
+
+struct CollectorState {
+    tracked: Vec<Tracked>,
+    next_gc: usize,
+    growth_factor: f64,
+    stress: bool,
+    stats: CycleStats,
+}
+
+impl Default for CollectorState {
+    fn default() -> Self {
+        CollectorState {
+            tracked: vec![],
+            next_gc: FIRST_GC,
+            growth_factor: 2.0,
+            stress: false,
+            stats: CycleStats::default(),
+        }
+    }
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// track_environment has the collector look after an environment that a
+    /// function has closed over, which may first collect garbage
+    pub fn track_environment(&self, environment: &Rc<RefCell<EvalState>>) {
+        self.track(Tracked::Environment(Rc::downgrade(environment)));
+    }
+
+    /// track_instance has the collector look after a new instance, which may
+    /// first collect garbage
+    pub fn track_instance(&self, instance: &Rc<LoxInstance>) {
+        self.track(Tracked::Instance(Rc::downgrade(instance)));
+    }
+
+    fn track(&self, object: Tracked) {
+        let should_collect = {
+            let mut state = self.0.borrow_mut();
+            state.stats.objects_tracked += 1;
+            state.tracked.push(object);
+            state.stress || state.tracked.len() > state.next_gc
+        };
+        if should_collect {
+            self.collect();
+        }
+    }
+
+    /// set_stress makes the collector run every time an environment or
+    /// instance is tracked, to shake out any it frees while they are still in
+    /// use
+    pub fn set_stress(&self, stress: bool) {
+        self.0.borrow_mut().stress = stress;
+    }
+
+    /// set_growth_factor sets how much the tracked objects may grow after a
+    /// collection before the next one, as a multiple of what survived it
+    pub fn set_growth_factor(&self, growth_factor: f64) {
+        self.0.borrow_mut().growth_factor = growth_factor;
+    }
+
+    /// stats counts the environments and instances tracked and freed. Objects
+    /// freed by reference counting are counted by the next collection, as that
+    /// is when the collector notices them.
+    pub fn stats(&self) -> CycleStats {
+        self.0.borrow().stats
+    }
+
+    /// collect frees every environment and instance that is only kept alive by
+    /// cycles of references
+    pub fn collect(&self) {
+        // Freeing garbage drops environments, so the state isn't borrowed while
+        // that happens
+        let tracked = mem::take(&mut self.0.borrow_mut().tracked);
+        free_cycles(&tracked);
+
+        let count = tracked.len();
+        let alive: Vec<Tracked> = tracked.into_iter().filter(Tracked::is_alive).collect();
+        let mut state = self.0.borrow_mut();
+        state.stats.collections += 1;
+        state.stats.objects_freed += count - alive.len();
+        state.next_gc = ((alive.len() as f64 * state.growth_factor) as usize).max(FIRST_GC);
+        state.tracked = alive;
+    }
+}
+
+// An object that can be part of a cycle, functions and classes being reached
+// through the environments and instances that hold them
+enum Node {
+    Environment(Rc<RefCell<EvalState>>),
+    Instance(Rc<LoxInstance>),
+    Function(Rc<LoxFunction>),
+    Class(Rc<LoxClass>),
+}
+
+impl Node {
+    fn from_value(value: &Value) -> Option<Node> {
+        match value {
+            Value::Function(function) => Some(Node::Function(Rc::clone(function))),
+            Value::Class(class) => Some(Node::Class(Rc::clone(class))),
+            Value::Instance(instance) => Some(Node::Instance(Rc::clone(instance))),
+            _ => None,
+        }
+    }
+
+    // Nodes are told apart by the address of their Rc allocation
+    fn key(&self) -> usize {
+        match self {
+            Node::Environment(environment) => Rc::as_ptr(environment) as *const () as usize,
+            Node::Instance(instance) => Rc::as_ptr(instance) as *const () as usize,
+            Node::Function(function) => Rc::as_ptr(function) as *const () as usize,
+            Node::Class(class) => Rc::as_ptr(class) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(environment) => Rc::strong_count(environment),
+            Node::Instance(instance) => Rc::strong_count(instance),
+            Node::Function(function) => Rc::strong_count(function),
+            Node::Class(class) => Rc::strong_count(class),
+        }
+    }
+
+    // The nodes this one refers to, once for each reference. None when it is
+    // being changed, as then what it refers to can't be seen.
+    fn references(&self) -> Option<Vec<Node>> {
+        match self {
+            Node::Environment(environment) => {
+                let environment = environment.try_borrow().ok()?;
+                let parent = environment
+                    .parent()
+                    .map(|parent| Node::Environment(Rc::clone(parent)));
+                Some(
+                    parent
+                        .into_iter()
+                        .chain(environment.values().filter_map(Node::from_value))
+                        .collect(),
+                )
+            }
+            Node::Instance(instance) => {
+                let fields = instance.fields.try_borrow().ok()?;
+                let class = Node::Class(Rc::clone(&instance.class));
+                Some(
+                    std::iter::once(class)
+                        .chain(fields.values().filter_map(Node::from_value))
+                        .collect(),
+                )
+            }
+            Node::Function(function) => Some(vec![Node::Environment(Rc::clone(&function.closure))]),
+            Node::Class(class) => {
+                let superclass = class
+                    .superclass
+                    .as_ref()
+                    .map(|superclass| Node::Class(Rc::clone(superclass)));
+                let methods = class
+                    .methods
+                    .values()
+                    .map(|method| Node::Function(Rc::clone(method)));
+                Some(superclass.into_iter().chain(methods).collect())
+            }
+        }
+    }
+
+    // Drop everything a garbage environment or instance refers to
+    fn clear(&self) {
+        match self {
+            Node::Environment(environment) => environment.borrow_mut().clear(),
+            Node::Instance(instance) => instance.fields.borrow_mut().clear(),
+            Node::Function(_) | Node::Class(_) => {}
+        }
+    }
+}
+
+fn free_cycles(tracked: &[Tracked]) {
+    // Find every node reachable from the tracked objects, keeping exactly one
+    // reference to each so that the reference counts can be accounted for
+    let mut nodes: HashMap<usize, Node> = HashMap::new();
+    let mut pending = vec![];
+    for node in tracked.iter().filter_map(Tracked::upgrade) {
+        if let Entry::Vacant(entry) = nodes.entry(node.key()) {
+            pending.push(entry.key().to_owned());
+            entry.insert(node);
+        }
+    }
+    let mut edges: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut hidden = HashSet::new();
+    while let Some(key) = pending.pop() {
+        let Some(references) = nodes[&key].references() else {
+            hidden.insert(key);
+            continue;
+        };
+        let mut children = vec![];
+        for reference in references {
+            let child = reference.key();
+            children.push(child);
+            if let Entry::Vacant(entry) = nodes.entry(child) {
+                entry.insert(reference);
+                pending.push(child);
+            }
+        }
+        edges.insert(key, children);
+    }
+
+    // A node with more references than those from other nodes, and the one
+    // held here, is referred to from outside of them. Those, and nodes whose
+    // references are hidden, are the roots.
+    let mut internal: HashMap<usize, usize> = HashMap::new();
+    for child in edges.values().flatten() {
+        *internal.entry(*child).or_default() += 1;
+    }
+    let mut live: HashSet<usize> = nodes
+        .iter()
+        .filter(|(key, node)| {
+            hidden.contains(*key)
+                || node.strong_count() > internal.get(*key).copied().unwrap_or(0) + 1
+        })
+        .map(|(key, _)| *key)
+        .collect();
+    let mut gray: Vec<usize> = live.iter().copied().collect();
+    while let Some(key) = gray.pop() {
+        for child in edges.get(&key).into_iter().flatten() {
+            if live.insert(*child) {
+                gray.push(*child);
+            }
+        }
+    }
+
+    // Emptying the garbage breaks its cycles, it is freed as the nodes are dropped
+    for (key, node) in &nodes {
+        if !live.contains(key) {
+            node.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::OutputBuffer;
+    use crate::interpreter::Interpreter;
+
+    #[test]
+    fn test_collect_cycles() {
+        let output = OutputBuffer::new();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        let source = "
+fun make() {
+  var a;
+  fun f() { return a; }
+  a = f;
+  return f;
+}
+class C {}
+var closure = make();
+var instance = C();
+instance.me = instance;
+var kept = make();
+";
+        interpreter.run_source(source).unwrap();
+        // Weak references show whether they have been freed
+        let environment = match interpreter.get_global("closure") {
+            Some(Value::Function(closure)) => Rc::downgrade(&closure.closure),
+            _ => panic!("expected a function"),
+        };
+        let instance = match interpreter.get_global("instance") {
+            Some(Value::Instance(instance)) => Rc::downgrade(&instance),
+            _ => panic!("expected an instance"),
+        };
+        interpreter.set_global("closure", Value::Nil);
+        interpreter.set_global("instance", Value::Nil);
+
+        // Reference counting alone leaves the cycles alive
+        assert!(environment.upgrade().is_some());
+        assert!(instance.upgrade().is_some());
+        let collector = EvalState::collector(&interpreter.globals()).unwrap();
+        let freed = collector.stats().objects_freed;
+        collector.collect();
+        assert!(environment.upgrade().is_none());
+        assert!(instance.upgrade().is_none());
+        // The closure's environment and the instance
+        assert_eq!(freed + 2, collector.stats().objects_freed);
+
+        // What is still in use is left alone
+        interpreter.run_source("print kept() == kept;").unwrap();
+        assert_eq!("true\n", output.contents());
+    }
+
+    #[test]
+    fn test_collector_per_interpreter() {
+        let mut stressed = Interpreter::with_output(Box::new(OutputBuffer::new()));
+        let other = Interpreter::with_output(Box::new(OutputBuffer::new()));
+        stressed.set_gc_stress(true);
+        stressed.run_source("class C {} var c = C();").unwrap();
+
+        // Only the interpreter whose collector is stressed collects, and only
+        // its stats count what it tracked
+        assert_eq!(1, stressed.gc_stats().collections);
+        assert_eq!(0, other.gc_stats().collections);
+        assert_eq!(0, other.gc_stats().objects_tracked);
+        assert_eq!(
+            "1 collections, 1 objects tracked, 0 objects freed",
+            stressed.gc_stats().to_string()
+        );
+    }
+}
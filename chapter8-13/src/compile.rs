@@ -0,0 +1,608 @@
+// Compiler for Lox
+// Turns resolved statements into bytecode for the virtual machine, following
+// the compiler of the book's clox. Instead of parsing tokens as it goes it
+// walks the syntax tree, but it keeps track of locals, upvalues and scopes in
+// the same way: locals live in stack slots, variables captured by closures
+// become upvalues and anything that is not found in an enclosing function is
+// a global, looked up by name.
+use crate::chunk::{Chunk, OpCode};
+use crate::intern::Symbol;
+use crate::object::{Function, Heap, Obj, Value, UpvalueRef};
+use crate::parse::{self, Expr, ExprKind, FunctionDecl, Identifier, Operator, Stmt, StmtKind};
+use crate::scan::Span;
+use std::fmt;
+use std::rc::Rc;
+
+// Operands are single bytes, which limits how many of each a function can have
+const MAX_CONSTANTS: usize = 256;
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl CompileError {
+    /// span is the location of the code that could not be compiled
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: Symbol,
+    depth: usize,
+    is_captured: bool,
+}
+
+// The function being compiled. Functions nest, so these form a stack with
+// the innermost function on top.
+struct FunctionState {
+    function_type: FunctionType,
+    name: String,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: &str) -> Self {
+        // Slot zero holds the function being called, or `this` in a method
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        FunctionState {
+            function_type,
+            name: name.to_string(),
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![Local {
+                name: Symbol::intern(slot_zero),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+struct CompileState<'a> {
+    heap: &'a mut Heap,
+    functions: Vec<FunctionState>,
+    errors: Vec<CompileError>,
+}
+
+/// Compile a program to the function that runs it, which is allocated on
+/// `heap` along with its constants. The statements must already have been
+/// through `resolve::resolve`, which reports the errors that clox's compiler
+/// would, leaving only the limits of the bytecode to be checked here.
+pub fn compile(stmts: &[Stmt], heap: &mut Heap) -> Result<Rc<Function>, Vec<CompileError>> {
+    let mut cs = CompileState {
+        heap,
+        functions: vec![FunctionState::new(FunctionType::Script, "")],
+        errors: vec![],
+    };
+    compile_statements(&mut cs, stmts);
+    let end = stmts.last().map_or(Span::default(), |stmt| stmt.span);
+    let function = end_function(&mut cs, end);
+    if cs.errors.is_empty() {
+        Ok(function)
+    } else {
+        Err(cs.errors)
+    }
+}
+
+fn error(cs: &mut CompileState, message: &str, span: Span) {
+    cs.errors.push(CompileError {
+        message: message.to_string(),
+        span,
+    });
+}
+
+fn current<'a>(cs: &'a mut CompileState) -> &'a mut FunctionState {
+    cs.functions.last_mut().expect("no function being compiled")
+}
+
+fn emit(cs: &mut CompileState, op: OpCode, span: Span) -> usize {
+    current(cs).chunk.write(op, span)
+}
+
+fn make_constant(cs: &mut CompileState, value: Value, span: Span) -> u8 {
+    let index = current(cs).chunk.add_constant(value);
+    if index >= MAX_CONSTANTS {
+        error(cs, "Too many constants in one chunk.", span);
+        return 0;
+    }
+    index as u8
+}
+
+// Names are looked up often, so each one is only added to a chunk once
+fn identifier_constant(cs: &mut CompileState, name: Symbol, span: Span) -> u8 {
+    let string = Value::Obj(cs.heap.intern(name.as_str()));
+    let existing = current(cs).chunk.constants.iter().position(|constant| *constant == string);
+    match existing {
+        Some(index) => index as u8,
+        None => make_constant(cs, string, span),
+    }
+}
+
+// Jumps are emitted before the code they jump over is known, with a
+// placeholder distance that is patched afterwards
+fn emit_jump(cs: &mut CompileState, op: fn(u16) -> OpCode, span: Span) -> usize {
+    emit(cs, op(u16::MAX), span)
+}
+
+fn patch_jump(cs: &mut CompileState, at: usize, span: Span) {
+    let distance = current(cs).chunk.code.len() - at - 1;
+    let Ok(distance) = u16::try_from(distance) else {
+        return error(cs, "Too much code to jump over.", span);
+    };
+    current(cs).chunk.code[at] = match current(cs).chunk.code[at] {
+        OpCode::Jump(_) => OpCode::Jump(distance),
+        OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(distance),
+        op => op,
+    };
+}
+
+fn emit_loop(cs: &mut CompileState, loop_start: usize, span: Span) {
+    let distance = current(cs).chunk.code.len() - loop_start + 1;
+    match u16::try_from(distance) {
+        Ok(distance) => {
+            emit(cs, OpCode::Loop(distance), span);
+        }
+        Err(_) => error(cs, "Loop body too large.", span),
+    }
+}
+
+fn begin_scope(cs: &mut CompileState) {
+    current(cs).scope_depth += 1;
+}
+
+// Locals going out of scope are popped off the stack, or moved off it into
+// their upvalue if a closure captured them
+fn end_scope(cs: &mut CompileState, span: Span) {
+    current(cs).scope_depth -= 1;
+    loop {
+        let function = current(cs);
+        match function.locals.last() {
+            Some(local) if local.depth > function.scope_depth => {
+                let op = match local.is_captured {
+                    true => OpCode::CloseUpvalue,
+                    false => OpCode::Pop,
+                };
+                function.locals.pop();
+                emit(cs, op, span);
+            }
+            _ => break,
+        }
+    }
+}
+
+fn add_local(cs: &mut CompileState, name: &Identifier) {
+    if current(cs).locals.len() >= MAX_LOCALS {
+        return error(cs, "Too many local variables in function.", name.span);
+    }
+    let function = current(cs);
+    let depth = function.scope_depth;
+    function.locals.push(Local {
+        name: name.name,
+        depth,
+        is_captured: false,
+    });
+}
+
+// Declare a variable whose value is on top of the stack. Locals simply stay
+// where they are, globals are moved into the table of globals.
+fn define_variable(cs: &mut CompileState, name: &Identifier) {
+    if current(cs).scope_depth > 0 {
+        add_local(cs, name);
+    } else {
+        let constant = identifier_constant(cs, name.name, name.span);
+        emit(cs, OpCode::DefineGlobal(constant), name.span);
+    }
+}
+
+fn resolve_local(function: &FunctionState, name: Symbol) -> Option<u8> {
+    function
+        .locals
+        .iter()
+        .rposition(|local| local.name == name)
+        .map(|slot| slot as u8)
+}
+
+// Find a variable in the functions enclosing the one at `depth` in the stack
+// of functions, adding an upvalue to each function between there and here
+fn resolve_upvalue(cs: &mut CompileState, depth: usize, name: Symbol, span: Span) -> Option<u8> {
+    let enclosing = depth.checked_sub(1)?;
+    if let Some(slot) = resolve_local(&cs.functions[enclosing], name) {
+        cs.functions[enclosing].locals[slot as usize].is_captured = true;
+        return Some(add_upvalue(cs, depth, slot, true, span));
+    }
+    let index = resolve_upvalue(cs, enclosing, name, span)?;
+    Some(add_upvalue(cs, depth, index, false, span))
+}
+
+fn add_upvalue(cs: &mut CompileState, depth: usize, index: u8, is_local: bool, span: Span) -> u8 {
+    let upvalue = UpvalueRef { index, is_local };
+    let upvalues = &mut cs.functions[depth].upvalues;
+    if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+        return existing as u8;
+    }
+    if upvalues.len() >= MAX_UPVALUES {
+        error(cs, "Too many closure variables in function.", span);
+        return 0;
+    }
+    upvalues.push(upvalue);
+    (upvalues.len() - 1) as u8
+}
+
+fn compile_get_variable(cs: &mut CompileState, name: Symbol, span: Span) {
+    let depth = cs.functions.len() - 1;
+    let op = if let Some(slot) = resolve_local(current(cs), name) {
+        OpCode::GetLocal(slot)
+    } else if let Some(index) = resolve_upvalue(cs, depth, name, span) {
+        OpCode::GetUpvalue(index)
+    } else {
+        OpCode::GetGlobal(identifier_constant(cs, name, span))
+    };
+    emit(cs, op, span);
+}
+
+fn compile_set_variable(cs: &mut CompileState, name: Symbol, span: Span) {
+    let depth = cs.functions.len() - 1;
+    let op = if let Some(slot) = resolve_local(current(cs), name) {
+        OpCode::SetLocal(slot)
+    } else if let Some(index) = resolve_upvalue(cs, depth, name, span) {
+        OpCode::SetUpvalue(index)
+    } else {
+        OpCode::SetGlobal(identifier_constant(cs, name, span))
+    };
+    emit(cs, op, span);
+}
+
+fn compile_statements(cs: &mut CompileState, stmts: &[Stmt]) {
+    for stmt in stmts {
+        compile_statement(cs, stmt);
+    }
+}
+
+fn compile_block(cs: &mut CompileState, stmts: &[Stmt], span: Span) {
+    begin_scope(cs);
+    compile_statements(cs, stmts);
+    end_scope(cs, span);
+}
+
+fn compile_statement(cs: &mut CompileState, stmt: &Stmt) {
+    let span = stmt.span;
+    match &stmt.kind {
+        StmtKind::VarDecl(name, initializer) => {
+            match initializer {
+                Some(expr) => compile_expression(cs, expr),
+                None => {
+                    emit(cs, OpCode::Nil, span);
+                }
+            }
+            define_variable(cs, name);
+        }
+        StmtKind::Function(declaration) => {
+            // A local function is declared first so that it can call itself
+            if current(cs).scope_depth > 0 {
+                add_local(cs, &declaration.name);
+                compile_function(cs, declaration, FunctionType::Function);
+            } else {
+                compile_function(cs, declaration, FunctionType::Function);
+                define_variable(cs, &declaration.name);
+            }
+        }
+        StmtKind::Class(name, superclass, methods) => {
+            let constant = identifier_constant(cs, name.name, name.span);
+            emit(cs, OpCode::Class(constant), span);
+            define_variable(cs, name);
+
+            // Methods of a subclass capture `super` from a scope around them
+            if let Some(superclass) = superclass {
+                compile_get_variable(cs, superclass.name, superclass.span);
+                begin_scope(cs);
+                add_local(
+                    cs,
+                    &Identifier {
+                        name: Symbol::intern("super"),
+                        span: superclass.span,
+                    },
+                );
+                compile_get_variable(cs, name.name, name.span);
+                emit(cs, OpCode::Inherit, superclass.span);
+            }
+
+            compile_get_variable(cs, name.name, name.span);
+            for method in methods {
+                let function_type = match method.name.name.as_str() {
+                    "init" => FunctionType::Initializer,
+                    _ => FunctionType::Method,
+                };
+                compile_function(cs, method, function_type);
+                let constant = identifier_constant(cs, method.name.name, method.name.span);
+                emit(cs, OpCode::Method(constant), method.name.span);
+            }
+            emit(cs, OpCode::Pop, span);
+
+            if superclass.is_some() {
+                end_scope(cs, span);
+            }
+        }
+        StmtKind::Block(stmts) => compile_block(cs, stmts, span),
+        StmtKind::Print(expr) => {
+            compile_expression(cs, expr);
+            emit(cs, OpCode::Print, span);
+        }
+        StmtKind::Expression(expr) => {
+            compile_expression(cs, expr);
+            emit(cs, OpCode::Pop, span);
+        }
+        StmtKind::If(cond, then_stmts, else_stmts) => {
+            compile_expression(cs, cond);
+            let then_jump = emit_jump(cs, OpCode::JumpIfFalse, span);
+            emit(cs, OpCode::Pop, span);
+            compile_block(cs, then_stmts, span);
+            let else_jump = emit_jump(cs, OpCode::Jump, span);
+            patch_jump(cs, then_jump, span);
+            emit(cs, OpCode::Pop, span);
+            compile_block(cs, else_stmts, span);
+            patch_jump(cs, else_jump, span);
+        }
+        StmtKind::While(cond, stmts) => {
+            let loop_start = current(cs).chunk.code.len();
+            compile_expression(cs, cond);
+            let exit_jump = emit_jump(cs, OpCode::JumpIfFalse, span);
+            emit(cs, OpCode::Pop, span);
+            compile_block(cs, stmts, span);
+            emit_loop(cs, loop_start, span);
+            patch_jump(cs, exit_jump, span);
+            emit(cs, OpCode::Pop, span);
+        }
+        StmtKind::Return(expr) => {
+            match expr {
+                Some(expr) => compile_expression(cs, expr),
+                None => emit_return_value(cs, span),
+            }
+            emit(cs, OpCode::Return, span);
+        }
+    }
+}
+
+// What a function returns when it doesn't say, an initializer always returns
+// the instance
+fn emit_return_value(cs: &mut CompileState, span: Span) {
+    match current(cs).function_type {
+        FunctionType::Initializer => emit(cs, OpCode::GetLocal(0), span),
+        _ => emit(cs, OpCode::Nil, span),
+    };
+}
+
+fn end_function(cs: &mut CompileState, span: Span) -> Rc<Function> {
+    emit_return_value(cs, span);
+    emit(cs, OpCode::Return, span);
+    let state = cs.functions.pop().expect("no function being compiled");
+    Rc::new(Function {
+        name: state.name,
+        arity: state.arity,
+        chunk: state.chunk,
+        upvalues: state.upvalues,
+    })
+}
+
+// Compile the body of a function into its own chunk, then emit the code that
+// makes a closure of it where it is declared
+fn compile_function(cs: &mut CompileState, declaration: &FunctionDecl, function_type: FunctionType) {
+    let span = declaration.name.span;
+    let mut state = FunctionState::new(function_type, declaration.name.name.as_str());
+    state.arity = declaration.params.len();
+    state.scope_depth = 1;
+    cs.functions.push(state);
+    for param in &declaration.params {
+        add_local(cs, param);
+    }
+    compile_statements(cs, &declaration.body);
+    let end = declaration.body.last().map_or(span, |stmt| stmt.span);
+    let function = end_function(cs, end);
+
+    let function = cs.heap.alloc(Obj::Function(function));
+    let constant = make_constant(cs, Value::Obj(function), span);
+    emit(cs, OpCode::Closure(constant), span);
+}
+
+fn compile_expression(cs: &mut CompileState, expr: &Expr) {
+    let span = expr.span;
+    match &expr.kind {
+        ExprKind::Literal(value) => compile_literal(cs, value, span),
+        ExprKind::Grouping(expr) => compile_expression(cs, expr),
+        ExprKind::Unary(operator, right) => {
+            compile_expression(cs, right);
+            let op = match operator {
+                Operator::Bang => OpCode::Not,
+                _ => OpCode::Negate,
+            };
+            emit(cs, op, span);
+        }
+        ExprKind::Binary(left, operator, right) => {
+            compile_expression(cs, left);
+            compile_expression(cs, right);
+            let op = match operator {
+                Operator::Plus => OpCode::Add,
+                Operator::Minus => OpCode::Subtract,
+                Operator::Star => OpCode::Multiply,
+                Operator::Slash => OpCode::Divide,
+                Operator::Greater => OpCode::Greater,
+                Operator::GreaterEqual => OpCode::GreaterEqual,
+                Operator::Less => OpCode::Less,
+                Operator::LessEqual => OpCode::LessEqual,
+                _ => OpCode::Equal,
+            };
+            emit(cs, op, span);
+            if matches!(operator, Operator::BangEqual) {
+                emit(cs, OpCode::Not, span);
+            }
+        }
+        // The left operand is left on the stack as the result if it decides
+        // the answer, otherwise it is popped and the right one evaluated
+        ExprKind::Logical(left, Operator::And, right) => {
+            compile_expression(cs, left);
+            let end_jump = emit_jump(cs, OpCode::JumpIfFalse, span);
+            emit(cs, OpCode::Pop, span);
+            compile_expression(cs, right);
+            patch_jump(cs, end_jump, span);
+        }
+        ExprKind::Logical(left, _, right) => {
+            compile_expression(cs, left);
+            let else_jump = emit_jump(cs, OpCode::JumpIfFalse, span);
+            let end_jump = emit_jump(cs, OpCode::Jump, span);
+            patch_jump(cs, else_jump, span);
+            emit(cs, OpCode::Pop, span);
+            compile_expression(cs, right);
+            patch_jump(cs, end_jump, span);
+        }
+        ExprKind::Variable(var) | ExprKind::This(var) => compile_get_variable(cs, var.name, span),
+        ExprKind::Assign(var, value) => {
+            compile_expression(cs, value);
+            compile_set_variable(cs, var.name, span);
+        }
+        ExprKind::Call(callee, arguments) => {
+            compile_expression(cs, callee);
+            for argument in arguments {
+                compile_expression(cs, argument);
+            }
+            // The parser allows at most 255 arguments
+            emit(cs, OpCode::Call(arguments.len() as u8), span);
+        }
+        ExprKind::Interpolation(parts) => {
+            for part in parts {
+                compile_expression(cs, part);
+            }
+            // The parser allows at most 255 parts
+            emit(cs, OpCode::Interpolate(parts.len() as u8), span);
+        }
+        ExprKind::Get(object, name) => {
+            compile_expression(cs, object);
+            let constant = identifier_constant(cs, *name, span);
+            emit(cs, OpCode::GetProperty(constant), span);
+        }
+        ExprKind::Set(object, name, value) => {
+            compile_expression(cs, object);
+            compile_expression(cs, value);
+            let constant = identifier_constant(cs, *name, span);
+            emit(cs, OpCode::SetProperty(constant), span);
+        }
+        ExprKind::Super(var, method) => {
+            compile_get_variable(cs, Symbol::intern("this"), span);
+            compile_get_variable(cs, var.name, span);
+            let constant = identifier_constant(cs, *method, span);
+            emit(cs, OpCode::GetSuper(constant), span);
+        }
+    }
+}
+
+fn compile_literal(cs: &mut CompileState, value: &parse::Value, span: Span) {
+    let op = match value {
+        parse::Value::Nil => OpCode::Nil,
+        parse::Value::Boolean(true) => OpCode::True,
+        parse::Value::Boolean(false) => OpCode::False,
+        parse::Value::Number(n) => OpCode::Constant(make_constant(cs, Value::Number(*n), span)),
+        parse::Value::String(string) => {
+            let string = cs.heap.intern(string);
+            OpCode::Constant(make_constant(cs, Value::Obj(string), span))
+        }
+        // The parser only makes literals of the values above
+        _ => OpCode::Nil,
+    };
+    emit(cs, op, span);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+    use crate::resolve::resolve;
+    use crate::scan::scan;
+
+    fn compile_source(source: &str, heap: &mut Heap) -> Result<Rc<Function>, Vec<CompileError>> {
+        let stmts = parse(&scan(source).unwrap()).unwrap();
+        resolve(&stmts).unwrap();
+        compile(&stmts, heap)
+    }
+
+    #[test]
+    fn test_compile_locals_and_globals() {
+        let mut heap = Heap::new();
+        let function = compile_source("var a = 1; { var b = a; print b; }", &mut heap).unwrap();
+
+        use OpCode::*;
+        assert_eq!(
+            vec![
+                Constant(0),
+                DefineGlobal(1),
+                GetGlobal(1),
+                GetLocal(1),
+                Print,
+                Pop,
+                Nil,
+                Return
+            ],
+            function.chunk.code
+        );
+        assert_eq!(function.chunk.code.len(), function.chunk.spans.len());
+    }
+
+    #[test]
+    fn test_compile_upvalues() {
+        let mut heap = Heap::new();
+        let source = "fun outer() { { var x = 1; fun middle() { fun inner() { return x; } } } }";
+        let script = compile_source(source, &mut heap).unwrap();
+
+        let function_at = |chunk: &Chunk, index: usize| match chunk.constants[index] {
+            Value::Obj(obj) => match heap.get(obj) {
+                Obj::Function(function) => Rc::clone(function),
+                _ => panic!("not a function"),
+            },
+            _ => panic!("not an object"),
+        };
+        let outer = function_at(&script.chunk, 0);
+        let middle = function_at(&outer.chunk, 1);
+        let inner = function_at(&middle.chunk, 0);
+        // x is captured from outer's stack into middle, then from middle's upvalues,
+        // and moved off the stack when its block ends
+        let local = UpvalueRef { index: 1, is_local: true };
+        let upvalue = UpvalueRef { index: 0, is_local: false };
+        assert_eq!(vec![local], middle.upvalues);
+        assert_eq!(vec![upvalue], inner.upvalues);
+        assert!(outer.chunk.code.contains(&OpCode::CloseUpvalue));
+    }
+
+    #[test]
+    fn test_compile_limits() {
+        let mut heap = Heap::new();
+        let numbers: Vec<String> = (0..300).map(|n| n.to_string()).collect();
+        let source = format!("print {};", numbers.join(" + "));
+        let errors = compile_source(&source, &mut heap).unwrap_err();
+        assert_eq!("Too many constants in one chunk.", errors[0].to_string());
+    }
+}
@@ -0,0 +1,192 @@
+// Disassembler for the virtual machine's bytecode
+// Prints chunks in the same layout as clox's debug.c: the offset of each
+// instruction, its source line (or | when it is the same as the one before),
+// the opcode and its operands, with constants shown by value.
+use crate::chunk::{Chunk, OpCode};
+use crate::compile::{compile, CompileError};
+use crate::object::{Function, Heap, Obj, Value};
+use crate::parse::Stmt;
+use std::rc::Rc;
+
+/// Compile a program and disassemble it, the script first and then every
+/// function in it in the order they appear
+pub fn disassemble_program(stmts: &[Stmt]) -> Result<String, Vec<CompileError>> {
+    let mut heap = Heap::new();
+    let script = compile(stmts, &mut heap)?;
+    let mut out = String::new();
+    let mut functions = vec![script];
+    while let Some(function) = functions.pop() {
+        out.push_str(&disassemble_chunk(&function.chunk, &function.to_string(), &heap));
+        // Nested functions are pushed in reverse to come out in source order
+        functions.extend(nested_functions(&function.chunk, &heap).into_iter().rev());
+    }
+    Ok(out)
+}
+
+fn nested_functions(chunk: &Chunk, heap: &Heap) -> Vec<Rc<Function>> {
+    chunk
+        .constants
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Obj(obj) => match heap.get(*obj) {
+                Obj::Function(function) => Some(Rc::clone(function)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Disassemble every instruction in `chunk` under a heading of `name`
+pub fn disassemble_chunk(chunk: &Chunk, name: &str, heap: &Heap) -> String {
+    let mut out = format!("== {} ==\n", name);
+    for offset in 0..chunk.code.len() {
+        out.push_str(&disassemble_instruction(chunk, offset, heap));
+    }
+    out
+}
+
+/// Disassemble the instruction at `offset` in `chunk`, including the upvalues
+/// captured by a closure, which are shown on lines of their own
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> String {
+    let line = chunk.spans[offset].line;
+    let mut out = match offset {
+        0 => format!("{:04} {:4} ", offset, line),
+        _ if chunk.spans[offset - 1].line == line => format!("{:04}    | ", offset),
+        _ => format!("{:04} {:4} ", offset, line),
+    };
+    let constant = |index: u8| heap.display(chunk.constants[index as usize]).to_string();
+    let op = chunk.code[offset];
+    let name = op_name(op);
+    let operands = match op {
+        OpCode::Constant(index)
+        | OpCode::GetGlobal(index)
+        | OpCode::DefineGlobal(index)
+        | OpCode::SetGlobal(index)
+        | OpCode::GetProperty(index)
+        | OpCode::SetProperty(index)
+        | OpCode::GetSuper(index)
+        | OpCode::Class(index)
+        | OpCode::Method(index) => format!("{:-16} {:4} '{}'", name, index, constant(index)),
+        OpCode::GetLocal(slot)
+        | OpCode::SetLocal(slot)
+        | OpCode::GetUpvalue(slot)
+        | OpCode::SetUpvalue(slot)
+        | OpCode::Call(slot)
+        | OpCode::Interpolate(slot) => format!("{:-16} {:4}", name, slot),
+        OpCode::Jump(distance) | OpCode::JumpIfFalse(distance) => {
+            format!("{:-16} {:4} -> {}", name, offset, offset + 1 + distance as usize)
+        }
+        OpCode::Loop(distance) => {
+            format!("{:-16} {:4} -> {}", name, offset, offset + 1 - distance as usize)
+        }
+        OpCode::Closure(index) => {
+            let mut text = format!("{:-16} {:4} {}", name, index, constant(index));
+            if let Value::Obj(obj) = chunk.constants[index as usize]
+                && let Obj::Function(function) = heap.get(obj)
+            {
+                for upvalue in &function.upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    text.push_str(&format!(
+                        "\n{:04}      |                     {} {}",
+                        offset, kind, upvalue.index
+                    ));
+                }
+            }
+            text
+        }
+        _ => name.to_string(),
+    };
+    out.push_str(&operands);
+    out.push('\n');
+    out
+}
+
+fn op_name(op: OpCode) -> &'static str {
+    match op {
+        OpCode::Constant(_) => "OP_CONSTANT",
+        OpCode::Nil => "OP_NIL",
+        OpCode::True => "OP_TRUE",
+        OpCode::False => "OP_FALSE",
+        OpCode::Pop => "OP_POP",
+        OpCode::GetLocal(_) => "OP_GET_LOCAL",
+        OpCode::SetLocal(_) => "OP_SET_LOCAL",
+        OpCode::GetGlobal(_) => "OP_GET_GLOBAL",
+        OpCode::DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+        OpCode::SetGlobal(_) => "OP_SET_GLOBAL",
+        OpCode::GetUpvalue(_) => "OP_GET_UPVALUE",
+        OpCode::SetUpvalue(_) => "OP_SET_UPVALUE",
+        OpCode::GetProperty(_) => "OP_GET_PROPERTY",
+        OpCode::SetProperty(_) => "OP_SET_PROPERTY",
+        OpCode::GetSuper(_) => "OP_GET_SUPER",
+        OpCode::Equal => "OP_EQUAL",
+        OpCode::Greater => "OP_GREATER",
+        OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+        OpCode::Less => "OP_LESS",
+        OpCode::LessEqual => "OP_LESS_EQUAL",
+        OpCode::Add => "OP_ADD",
+        OpCode::Subtract => "OP_SUBTRACT",
+        OpCode::Multiply => "OP_MULTIPLY",
+        OpCode::Divide => "OP_DIVIDE",
+        OpCode::Not => "OP_NOT",
+        OpCode::Negate => "OP_NEGATE",
+        OpCode::Print => "OP_PRINT",
+        OpCode::Interpolate(_) => "OP_INTERPOLATE",
+        OpCode::Jump(_) => "OP_JUMP",
+        OpCode::JumpIfFalse(_) => "OP_JUMP_IF_FALSE",
+        OpCode::Loop(_) => "OP_LOOP",
+        OpCode::Call(_) => "OP_CALL",
+        OpCode::Closure(_) => "OP_CLOSURE",
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+        OpCode::Return => "OP_RETURN",
+        OpCode::Class(_) => "OP_CLASS",
+        OpCode::Inherit => "OP_INHERIT",
+        OpCode::Method(_) => "OP_METHOD",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_disassemble_program() {
+        let source = "var a = 1;\nwhile (a < 3) a = a + 1;\nfun f(x) {\n  fun g() { return x; }\n}";
+        let stmts = Interpreter::compile(source).unwrap();
+
+        let expected = "\
+== <script> ==
+0000    1 OP_CONSTANT         0 '1'
+0001    | OP_DEFINE_GLOBAL    1 'a'
+0002    2 OP_GET_GLOBAL       1 'a'
+0003    | OP_CONSTANT         2 '3'
+0004    | OP_LESS
+0005    | OP_JUMP_IF_FALSE    5 -> 13
+0006    | OP_POP
+0007    | OP_GET_GLOBAL       1 'a'
+0008    | OP_CONSTANT         3 '1'
+0009    | OP_ADD
+0010    | OP_SET_GLOBAL       1 'a'
+0011    | OP_POP
+0012    | OP_LOOP            12 -> 2
+0013    | OP_POP
+0014    3 OP_CLOSURE          4 <fn f>
+0015    | OP_DEFINE_GLOBAL    5 'f'
+0016    | OP_NIL
+0017    | OP_RETURN
+== <fn f> ==
+0000    4 OP_CLOSURE          0 <fn g>
+0000      |                     local 1
+0001    | OP_NIL
+0002    | OP_RETURN
+== <fn g> ==
+0000    4 OP_GET_UPVALUE      0
+0001    | OP_RETURN
+0002    | OP_NIL
+0003    | OP_RETURN
+";
+        assert_eq!(expected, disassemble_program(&stmts).unwrap());
+    }
+}
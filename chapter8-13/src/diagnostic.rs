@@ -0,0 +1,229 @@
+// Diagnostics for Lox
+// Renders errors from any stage of the interpreter in the style of rustc: the
+// kind of error and its message, where it happened, and the offending line of
+// source with carets under the part that caused it.
+use crate::compile::CompileError;
+use crate::eval::RuntimeError;
+use crate::parse::ParseError;
+use crate::resolve::ResolveError;
+use crate::scan::{ScanError, Span};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// An error message with the kind of error it is and, when known, the span of
+/// source it refers to
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: &'static str, message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            kind,
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl From<&ScanError> for Diagnostic {
+    fn from(err: &ScanError) -> Self {
+        Diagnostic::new("scan error", err.to_string(), Some(err.span()))
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(err: &ParseError) -> Self {
+        Diagnostic::new("parse error", err.to_string(), Some(err.span()))
+    }
+}
+
+impl From<&ResolveError> for Diagnostic {
+    fn from(err: &ResolveError) -> Self {
+        Diagnostic::new("resolve error", err.to_string(), Some(err.span()))
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(err: &CompileError) -> Self {
+        Diagnostic::new("compile error", err.to_string(), Some(err.span()))
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(err: &RuntimeError) -> Self {
+        Diagnostic::new("runtime error", err.to_string(), err.span())
+    }
+}
+
+/// Render `diagnostic` against the `source` it came from, which is called `name`
+/// in the output. With `colour` off no terminal escape codes are used, which is
+/// what you want when the output goes to a log file.
+///
+/// ```
+/// use rlox::diagnostic::{render, Diagnostic};
+/// use rlox::interpreter::{Interpreter, InterpreterError};
+///
+/// let source = "fun f() {\n  return -\"x\";\n}\nf();";
+/// let Err(InterpreterError::RuntimeError(err)) = Interpreter::new().run_source(source) else {
+///     panic!("expected a runtime error");
+/// };
+/// let rendered = render(&Diagnostic::from(&err), source, "negate.lox", false);
+/// assert_eq!(
+///     r#"runtime error: Operand must be a number.
+///  --> negate.lox:2:10
+///   |
+/// 2 |   return -"x";
+///   |          ^^^^
+/// "#,
+///     rendered
+/// );
+/// ```
+pub fn render(diagnostic: &Diagnostic, source: &str, name: &str, colour: bool) -> String {
+    let paint = |style: &str, text: &str| {
+        if colour {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut out = format!(
+        "{}{}\n",
+        paint(RED, diagnostic.kind),
+        paint(BOLD, &format!(": {}", diagnostic.message))
+    );
+    let Some(span) = diagnostic.span else {
+        return out;
+    };
+
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let line = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+
+    // Pad up to the column with the whitespace of the line itself so that tabs
+    // line up, then underline the part of the span on this line
+    let padding: String = line
+        .chars()
+        .take(span.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let remaining = line.chars().count().saturating_sub(span.column - 1);
+    let spanned = source
+        .get(span.offset..span.offset + span.len)
+        .and_then(|text| text.lines().next())
+        .map_or(span.len, |text| text.chars().count());
+    let carets = "^".repeat(spanned.min(remaining).max(1));
+
+    out.push_str(&format!(
+        "{}{} {}:{}:{}\n",
+        gutter,
+        paint(BLUE, "-->"),
+        name,
+        span.line,
+        span.column
+    ));
+    out.push_str(&format!("{} {}\n", gutter, paint(BLUE, "|")));
+    out.push_str(&format!("{} {}\n", paint(BLUE, &format!("{} |", line_number)), line));
+    out.push_str(&format!(
+        "{} {}{}\n",
+        gutter,
+        paint(BLUE, "|"),
+        paint(RED, &format!(" {}{}", padding, carets))
+    ));
+    out
+}
+
+/// Render `diagnostic` in the format of the book's jlox, which is what the book's
+/// test suite expects. Scan errors are `[line 1] Error: message`, other compile
+/// errors name the token at fault, as in `[line 1] Error at 'x': message` or
+/// `[line 1] Error at end: message`, and runtime errors give the line after the
+/// message.
+pub fn render_book(diagnostic: &Diagnostic, source: &str) -> String {
+    let Some(span) = diagnostic.span else {
+        return format!("{}\n", diagnostic.message);
+    };
+    match diagnostic.kind {
+        "runtime error" => format!("{}\n[line {}]\n", diagnostic.message, span.line),
+        "scan error" => format!("[line {}] Error: {}\n", span.line, diagnostic.message),
+        _ => {
+            let location = match source.get(span.offset..span.offset + span.len) {
+                Some("") | None => " at end".to_string(),
+                Some(lexeme) => format!(" at '{}'", lexeme),
+            };
+            format!(
+                "[line {}] Error{}: {}\n",
+                span.line, location, diagnostic.message
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_render_plain() {
+        let source = "var a = 1;\nprint a + nil;\n";
+        let diagnostic = Diagnostic::new(
+            "runtime error",
+            "Cannot add",
+            Some(Span::new(17, 7, 2, 7)),
+        );
+
+        let expected = "\
+runtime error: Cannot add
+ --> test.lox:2:7
+  |
+2 | print a + nil;
+  |       ^^^^^^^
+";
+        assert_eq!(expected, render(&diagnostic, source, "test.lox", false));
+    }
+
+    #[test]
+    fn test_render_edge_cases() {
+        // No span means there is nothing to point at
+        let diagnostic = Diagnostic::new("runtime error", "Stack overflow", None);
+        assert_eq!(
+            "runtime error: Stack overflow\n",
+            render(&diagnostic, "", "test.lox", false)
+        );
+
+        // A span running over several lines is underlined to the end of the first,
+        // and an empty span still gets a caret
+        let source = "print \"abc\ndef\";\nprint";
+        let multi_line = Diagnostic::new("parse error", "m", Some(Span::new(6, 9, 1, 7)));
+        let at_end = Diagnostic::new("parse error", "e", Some(Span::new(22, 0, 3, 6)));
+        assert!(render(&multi_line, source, "t", false).ends_with("|       ^^^^\n"));
+        assert!(render(&at_end, source, "t", false).ends_with("|      ^\n"));
+
+        // Colour uses escape codes
+        assert!(render(&at_end, source, "t", true).contains(RED));
+    }
+
+    #[test]
+    fn test_render_book() {
+        let source = "print a;\nprint ;";
+        let at = |kind, span| render_book(&Diagnostic::new(kind, "Oops.", Some(span)), source);
+
+        assert_eq!("[line 1] Error: Oops.\n", at("scan error", Span::new(6, 1, 1, 7)));
+        assert_eq!(
+            "[line 2] Error at ';': Oops.\n",
+            at("parse error", Span::new(15, 1, 2, 7))
+        );
+        assert_eq!(
+            "[line 2] Error at end: Oops.\n",
+            at("resolve error", Span::new(16, 0, 2, 8))
+        );
+        assert_eq!("Oops.\n[line 1]\n", at("runtime error", Span::new(6, 1, 1, 7)));
+    }
+}
@@ -1,12 +1,161 @@
-use crate::eval::Expr::{Assign, Binary, Call, Grouping, Literal, Logical, Unary, Variable};
+use crate::eval::ExprKind::{
+    Assign, Binary, Call, Get, Grouping, Interpolation, Literal, Logical, Set, Super, This, Unary,
+    Variable,
+};
+use crate::collect::Collector;
+use crate::intern::Symbol;
 use crate::parse::Operator;
-use crate::parse::{Expr, Stmt, Value};
-use std::cell::RefCell;
+use crate::parse::{Expr, ExprKind, FunctionDecl, Stmt, StmtKind, Value, VarRef};
+use crate::scan::Span;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// An error raised while running a program. The span is that of the innermost
+/// expression or statement being evaluated when the error happened, errors
+/// raised by native functions get the span of the call.
 #[derive(Debug)]
-pub struct RuntimeError(String);
+pub struct RuntimeError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    // Errors are created without a span and given the one of the nearest
+    // enclosing node as they propagate
+    fn or_span(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The most calls to Lox functions that can be in progress at once. Recursing
+/// any deeper is a "Stack overflow." error, as it is in the virtual machine.
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+// Evaluating a call takes a lot of the Rust stack, especially in a debug build,
+// so whenever less than the red zone is left a new segment is allocated for
+// the call rather than overflowing the stack
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT: usize = 2 * 1024 * 1024;
+
+thread_local! {
+    // How many calls to Lox functions are in progress
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A user defined function value, created when a `fun` declaration is executed.
+/// It holds on to the symbol table it was declared in so that free variables
+/// in the body are found there rather than in the caller's scope
+pub struct LoxFunction {
+    pub declaration: Rc<FunctionDecl>,
+    pub closure: Rc<RefCell<EvalState>>,
+    pub is_initializer: bool,
+}
+
+impl LoxFunction {
+    /// bind makes a method of this function, with `this` bound to `instance` in
+    /// a new scope between the closure and the body
+    pub fn bind(&self, instance: Rc<LoxInstance>) -> LoxFunction {
+        let mut this_state = EvalState::new_from_parent(Rc::clone(&self.closure));
+        this_state.define(Symbol::intern("this"), Some(Value::Instance(instance)));
+        LoxFunction {
+            declaration: Rc::clone(&self.declaration),
+            closure: Rc::new(RefCell::new(this_state)),
+            is_initializer: self.is_initializer,
+        }
+    }
+}
+
+// Written by hand so that printing a function doesn't print its whole body or
+// its closure, which may well contain the function itself
+impl fmt::Debug for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.declaration.name)
+    }
+}
+
+/// The Rust side of a native function, it receives the evaluated arguments
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, RuntimeError>;
+
+/// A function implemented in Rust and callable from Lox, see `EvalState::register_native`
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+/// A class value, created when a `class` declaration is executed. Calling it
+/// makes a new instance.
+pub struct LoxClass {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass>>,
+    pub methods: HashMap<Symbol, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    /// find_method looks for a method in this class then up the superclass chain
+    pub fn find_method(&self, name: Symbol) -> Option<Rc<LoxFunction>> {
+        match (self.methods.get(&name), &self.superclass) {
+            (Some(method), _) => Some(Rc::clone(method)),
+            (None, Some(superclass)) => superclass.find_method(name),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Debug for LoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// An instance of a class. Fields are created on assignment, so they live in a
+/// RefCell to allow mutation through the shared Rc.
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    pub fields: RefCell<HashMap<Symbol, Value>>,
+}
+
+impl fmt::Debug for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+// The outcome of executing a statement. Either carry on with the next one or
+// unwind to the enclosing function call with the returned value.
+enum Flow {
+    Next,
+    Return(Value),
+}
 
 // All values have a true or false value. The only things that are false in lox are nil and
 // boolean false, everything else is true
@@ -25,10 +174,55 @@ fn numeric_value(value: &Value) -> Option<f64> {
 
 type EvalResult = Result<Value, RuntimeError>;
 
-#[derive(Debug)]
+/// A symbol table. The outermost one, which holds the globals, is also the
+/// context of the whole interpreter and owns where `print` writes to and the
+/// cycle collector.
+///
+/// Environments are shared with Rc. A function declared in a block holds on
+/// to the block's environment, which holds on to the function, and the same
+/// goes for a class and its methods, so environments that functions close over
+/// are tracked by the cycle collector in `collect` to free them once nothing
+/// else refers to them.
 pub struct EvalState {
     parent: Option<Rc<RefCell<EvalState>>>,
-    symbols: HashMap<String, Option<Value>>,
+    symbols: HashMap<Symbol, Option<Value>>,
+    output: Option<Box<dyn Write>>,
+    collector: Option<Rc<Collector>>,
+    captured: bool,
+}
+
+// Written by hand as the output can't be printed
+impl fmt::Debug for EvalState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EvalState")
+            .field("parent", &self.parent)
+            .field("symbols", &self.symbols)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An in memory output that can be handed to `EvalState::with_output` and still
+/// read afterwards, as clones share the same buffer
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// contents is everything written so far
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl Default for EvalState {
@@ -38,114 +232,335 @@ impl Default for EvalState {
 }
 
 impl EvalState {
+    /// new creates the global symbol table, with the built in native functions
+    /// already registered, that prints to stdout
     pub fn new() -> Self {
-        EvalState {
+        EvalState::with_output(Box::new(io::stdout()))
+    }
+    /// with_output creates the global symbol table like `new`, but `print`
+    /// writes to `output` instead of stdout
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let mut eval_state = EvalState {
             parent: None,
             symbols: HashMap::new(),
-        }
+            output: Some(output),
+            collector: Some(Rc::new(Collector::new())),
+            captured: false,
+        };
+        eval_state.register_native("clock", 0, |_| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|err| RuntimeError::new(format!("Clock error: {}", err)))?;
+            Ok(Value::Number(now.as_secs_f64()))
+        });
+        eval_state
     }
     pub fn new_from_parent(parent: Rc<RefCell<EvalState>>) -> Self {
         EvalState {
             parent: Some(parent),
             symbols: HashMap::new(),
+            output: None,
+            collector: None,
+            captured: false,
         }
     }
     /// lookup finds the key in the current block's symbol table and
     /// then looks in the parent table and so on until it runs out of
     /// places to look
-    pub fn lookup(&self, key: &str) -> EvalResult {
-        match (self.symbols.get(&key.to_string()), &self.parent) {
+    pub fn lookup(&self, key: Symbol) -> EvalResult {
+        match (self.symbols.get(&key), &self.parent) {
             (Some(Some(value)), _) => Ok(value.clone()),
-            (Some(None), _) => Err(RuntimeError(format!(
+            (Some(None), _) => Err(RuntimeError::new(format!(
                 "Unitialized variable access: {}",
                 key
             ))),
             (None, Some(parent)) => parent.borrow().lookup(key),
-            (None, None) => Err(RuntimeError(format!("Unknown variable access: {}", key))),
+            (None, None) => Err(RuntimeError::new(format!("Undefined variable '{}'.", key))),
         }
     }
     /// assign gives variable `key` the value `value`, finding the variable
     /// in the same way that lookup does
-    pub fn assign(&mut self, key: &str, value: &Value) -> EvalResult {
-        let key_string = key.to_string();
-        let found = self.symbols.get(&key_string).is_some();
-
-        if found {
-            self.symbols.insert(key_string, Some(value.clone()));
+    pub fn assign(&mut self, key: Symbol, value: &Value) -> EvalResult {
+        if let Some(symbol) = self.symbols.get_mut(&key) {
+            *symbol = Some(value.clone());
             Ok(value.clone())
         } else {
             match &self.parent {
                 Some(p) => p.borrow_mut().assign(key, value),
-                None => Err(RuntimeError(format!(
-                    "Assignent to unknown variable {}",
-                    key
-                ))),
+                None => Err(RuntimeError::new(format!("Undefined variable '{}'.", key))),
             }
         }
     }
+    /// parent is the enclosing symbol table, if this isn't the global one
+    pub fn parent(&self) -> Option<&Rc<RefCell<EvalState>>> {
+        self.parent.as_ref()
+    }
+    /// values are the values of the variables in this table, for the cycle
+    /// collector to follow
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.symbols.values().flatten()
+    }
+    /// clear removes every variable and the parent, which the cycle collector
+    /// does to an environment that is garbage
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+        self.parent = None;
+    }
+    /// define creates (or shadows) the variable `key` in this block's symbol table
+    pub fn define(&mut self, key: Symbol, value: Option<Value>) {
+        self.symbols.insert(key, value);
+    }
+    /// register_native makes a Rust closure callable from Lox as a function called
+    /// `name`. The closure is only ever called with exactly `arity` arguments.
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        self.define(Symbol::intern(name), Some(Value::NativeFunction(Rc::new(native))));
+    }
+    /// globals walks up the parent chain to the outermost symbol table
+    pub fn globals(eval_state: &Rc<RefCell<EvalState>>) -> Rc<RefCell<EvalState>> {
+        match &eval_state.borrow().parent {
+            Some(parent) => EvalState::globals(parent),
+            None => Rc::clone(eval_state),
+        }
+    }
+    /// print writes a value and a newline to the output of the globals
+    pub fn print(eval_state: &Rc<RefCell<EvalState>>, value: &Value) -> Result<(), RuntimeError> {
+        let globals = EvalState::globals(eval_state);
+        let mut globals = globals.borrow_mut();
+        match globals.output.as_mut() {
+            Some(output) => writeln!(output, "{}", value)
+                .map_err(|err| RuntimeError::new(format!("Output error: {}", err))),
+            None => Err(RuntimeError::new("Globals have no output")),
+        }
+    }
+    /// collector is the cycle collector of the interpreter, which the globals own
+    pub fn collector(eval_state: &Rc<RefCell<EvalState>>) -> Option<Rc<Collector>> {
+        EvalState::globals(eval_state).borrow().collector.clone()
+    }
+    /// ancestor returns the symbol table `depth` parents up from this one
+    fn ancestor(
+        eval_state: &Rc<RefCell<EvalState>>,
+        depth: usize,
+    ) -> Result<Rc<RefCell<EvalState>>, RuntimeError> {
+        let mut current = Rc::clone(eval_state);
+        for _ in 0..depth {
+            let parent = match &current.borrow().parent {
+                Some(parent) => Rc::clone(parent),
+                None => {
+                    return Err(RuntimeError::new(format!(
+                        "Resolved scope depth {} is beyond the outermost scope",
+                        depth
+                    )))
+                }
+            };
+            current = parent;
+        }
+        Ok(current)
+    }
+    // The symbol table holding a variable, as worked out by the resolver. Variables
+    // it could not resolve are globals.
+    fn scope_of(
+        eval_state: &Rc<RefCell<EvalState>>,
+        var: &VarRef,
+    ) -> Result<Rc<RefCell<EvalState>>, RuntimeError> {
+        match var.depth.get() {
+            Some(depth) => EvalState::ancestor(eval_state, depth),
+            None => Ok(EvalState::globals(eval_state)),
+        }
+    }
+    /// lookup_var reads a resolved variable directly from the scope it was declared in
+    pub fn lookup_var(eval_state: &Rc<RefCell<EvalState>>, var: &VarRef) -> EvalResult {
+        let scope = EvalState::scope_of(eval_state, var)?;
+        scope.borrow().lookup(var.name)
+    }
+    /// assign_var writes a resolved variable directly in the scope it was declared in
+    pub fn assign_var(
+        eval_state: &Rc<RefCell<EvalState>>,
+        var: &VarRef,
+        value: &Value,
+    ) -> EvalResult {
+        let scope = EvalState::scope_of(eval_state, var)?;
+        scope.borrow_mut().assign(var.name, value)
+    }
 }
 
+/// Execute the statements of a program directly in the symbol table `eval_state`.
+/// They must already have been through `resolve::resolve` so that every local
+/// variable knows which scope it lives in.
 pub fn eval_statements(
     stmts: &[Stmt],
-    parent_eval_state: Rc<RefCell<EvalState>>,
+    eval_state: Rc<RefCell<EvalState>>,
 ) -> Result<(), RuntimeError> {
-    let eval_state = Rc::new(RefCell::new(EvalState::new_from_parent(Rc::clone(
-        &parent_eval_state,
-    ))));
+    match execute_statements(stmts, eval_state)? {
+        Flow::Next => Ok(()),
+        Flow::Return(_) => Err(RuntimeError::new("Can't return from top-level code")),
+    }
+}
 
+// Run statements in order, stopping early if one of them returns
+fn execute_statements(
+    stmts: &[Stmt],
+    eval_state: Rc<RefCell<EvalState>>,
+) -> Result<Flow, RuntimeError> {
     for stmt in stmts {
-        match stmt {
-            Stmt::VarDecl(id, Some(expr)) => match eval_expression(expr, Rc::clone(&eval_state)) {
-                Ok(value) => {
-                    eval_state
-                        .borrow_mut()
-                        .symbols
-                        .insert(id.to_string(), Some(value));
+        if let Flow::Return(value) = execute(stmt, Rc::clone(&eval_state))? {
+            return Ok(Flow::Return(value));
+        }
+    }
+    Ok(Flow::Next)
+}
+
+// Run statements in a new block scope whose parent is `parent_eval_state`
+fn execute_block(
+    stmts: &[Stmt],
+    parent_eval_state: Rc<RefCell<EvalState>>,
+) -> Result<Flow, RuntimeError> {
+    let eval_state = Rc::new(RefCell::new(EvalState::new_from_parent(parent_eval_state)));
+    execute_statements(stmts, eval_state)
+}
+
+// An environment can only be part of a cycle once a function closes over it,
+// as that is the only reference back to it from a value, so that is when the
+// cycle collector starts tracking it. Blocks and calls that declare no
+// functions are left to reference counting alone.
+fn capture(eval_state: &Rc<RefCell<EvalState>>) {
+    let newly_captured = !mem::replace(&mut eval_state.borrow_mut().captured, true);
+    if let Some(collector) = EvalState::collector(eval_state).filter(|_| newly_captured) {
+        collector.track_environment(eval_state);
+    }
+}
+
+fn execute(stmt: &Stmt, eval_state: Rc<RefCell<EvalState>>) -> Result<Flow, RuntimeError> {
+    execute_kind(&stmt.kind, eval_state).map_err(|err| err.or_span(stmt.span))
+}
+
+fn execute_kind(
+    stmt: &StmtKind,
+    eval_state: Rc<RefCell<EvalState>>,
+) -> Result<Flow, RuntimeError> {
+    match stmt {
+        StmtKind::VarDecl(id, Some(expr)) => {
+            let value = eval_expression(expr, Rc::clone(&eval_state))?;
+            eval_state.borrow_mut().define(id.name, Some(value));
+        }
+        // Variables declared without an initializer start out as nil
+        StmtKind::VarDecl(id, None) => {
+            eval_state.borrow_mut().define(id.name, Some(Value::Nil));
+        }
+        StmtKind::Function(declaration) => {
+            capture(&eval_state);
+            let function = LoxFunction {
+                declaration: Rc::clone(declaration),
+                closure: Rc::clone(&eval_state),
+                is_initializer: false,
+            };
+            eval_state
+                .borrow_mut()
+                .define(declaration.name.name, Some(Value::Function(Rc::new(function))));
+        }
+        StmtKind::Class(name, superclass_var, method_decls) => {
+            let superclass = match superclass_var {
+                Some(var) => match EvalState::lookup_var(&eval_state, var)? {
+                    Value::Class(class) => Some(class),
+                    _ => {
+                        return Err(RuntimeError::new("Superclass must be a class.").or_span(var.span))
+                    }
+                },
+                None => None,
+            };
+            // Methods of a subclass close over an extra scope holding `super`
+            let method_state = match &superclass {
+                Some(class) => {
+                    let mut super_state = EvalState::new_from_parent(Rc::clone(&eval_state));
+                    super_state.define(Symbol::intern("super"), Some(Value::Class(Rc::clone(class))));
+                    Rc::new(RefCell::new(super_state))
                 }
-                Err(err) => return Err(err),
-            },
-            Stmt::VarDecl(id, None) => {
-                eval_state.borrow_mut().symbols.insert(id.to_string(), None);
+                None => Rc::clone(&eval_state),
+            };
+            if !method_decls.is_empty() {
+                capture(&method_state);
             }
-            Stmt::Block(stmts) => {
-                eval_statements(stmts, Rc::clone(&eval_state))?;
+            let methods = method_decls
+                .iter()
+                .map(|declaration| {
+                    let method = LoxFunction {
+                        declaration: Rc::clone(declaration),
+                        closure: Rc::clone(&method_state),
+                        is_initializer: declaration.name.name == "init",
+                    };
+                    (declaration.name.name, Rc::new(method))
+                })
+                .collect();
+            let class = LoxClass {
+                name: name.name.to_string(),
+                superclass,
+                methods,
+            };
+            eval_state
+                .borrow_mut()
+                .define(name.name, Some(Value::Class(Rc::new(class))));
+        }
+        StmtKind::Block(stmts) => return execute_block(stmts, eval_state),
+        StmtKind::Print(expr) => {
+            let value = eval_expression(expr, Rc::clone(&eval_state))?;
+            EvalState::print(&eval_state, &value)?;
+        }
+        StmtKind::Expression(expr) => {
+            eval_expression(expr, eval_state)?;
+        }
+        StmtKind::If(expr, then_stmt, else_stmt) => {
+            let cond = eval_expression(expr, Rc::clone(&eval_state))?;
+            let cond_bool = bool_value(&cond);
+            if cond_bool {
+                return execute_block(then_stmt, eval_state);
+            } else {
+                return execute_block(else_stmt, eval_state);
             }
-            // Print can become a builtin native
-            Stmt::Print(expr) => match eval_expression(expr, Rc::clone(&eval_state)) {
-                Ok(value) => println!("{}", value),
-                Err(err) => return Err(err),
-            },
-            Stmt::Expression(expr) => match eval_expression(expr, Rc::clone(&eval_state)) {
-                Ok(_) => (),
-                Err(err) => return Err(err),
-            },
-            Stmt::If(expr, then_stmt, else_stmt) => {
-                let cond = eval_expression(expr, Rc::clone(&eval_state))?;
-                let cond_bool = bool_value(&cond);
-                if cond_bool {
-                    eval_statements(then_stmt, Rc::clone(&eval_state))?
-                } else {
-                    eval_statements(else_stmt, Rc::clone(&eval_state))?
+        }
+        StmtKind::While(expr, stmts) => loop {
+            let cond = eval_expression(expr, Rc::clone(&eval_state))?;
+            let cond_bool = bool_value(&cond);
+            if cond_bool {
+                if let Flow::Return(value) = execute_block(stmts, Rc::clone(&eval_state))? {
+                    return Ok(Flow::Return(value));
                 }
+            } else {
+                break;
             }
-            Stmt::While(expr, stmts) => loop {
-                let cond = eval_expression(expr, Rc::clone(&eval_state))?;
-                let cond_bool = bool_value(&cond);
-                if cond_bool {
-                    eval_statements(stmts, Rc::clone(&eval_state))?
-                } else {
-                    break;
-                }
-            },
+        },
+        StmtKind::Return(expr) => {
+            let value = match expr {
+                Some(expr) => eval_expression(expr, eval_state)?,
+                None => Value::Nil,
+            };
+            return Ok(Flow::Return(value));
         }
     }
-    Ok(())
+    Ok(Flow::Next)
 }
 
-#[rustfmt::skip]
 pub fn eval_expression(expr: &Expr, eval_state: Rc<RefCell<EvalState>>) -> EvalResult {
+    eval_expression_kind(&expr.kind, eval_state).map_err(|err| err.or_span(expr.span))
+}
+
+#[rustfmt::skip]
+fn eval_expression_kind(expr: &ExprKind, eval_state: Rc<RefCell<EvalState>>) -> EvalResult {
     match expr {
         Literal(value) => Ok(value.clone()),
+        Interpolation(parts) => {
+            let mut string = String::new();
+            for part in parts {
+                string.push_str(&eval_expression(part, Rc::clone(&eval_state))?.to_string());
+            }
+            Ok(Value::String(string.into()))
+        },
         Call(callee, arguments) => eval_call(callee, arguments, Rc::clone(&eval_state)),
         Unary(operator, right) => {
             let right = eval_expression(right,eval_state)?;
@@ -159,11 +574,11 @@ pub fn eval_expression(expr: &Expr, eval_state: Rc<RefCell<EvalState>>) -> EvalR
                         Some(n) =>
                             Ok(Value::Number(-n)),
                         None => 
-                            Err(RuntimeError(format!("Cannot negate {:?}", right)))
+                            Err(RuntimeError::new("Operand must be a number."))
                     }
                 },
                 thing  => {
-                    Err(RuntimeError(format!("Unary inappropriate for {:?}", thing)))
+                    Err(RuntimeError::new(format!("Unary inappropriate for {:?}", thing)))
                 },
             }
         },
@@ -180,15 +595,15 @@ pub fn eval_expression(expr: &Expr, eval_state: Rc<RefCell<EvalState>>) -> EvalR
                 Operator::EqualEqual => eval_equality_operator(left,right,false),
                 Operator::BangEqual => eval_equality_operator(left,right,true),
                 // Comparison operators
-                Operator::Greater => eval_comparison_operator(left,right,left_number,right_number,">",|(a,b)| a > b),
-                Operator::GreaterEqual => eval_comparison_operator(left,right,left_number,right_number,">=",|(a,b)| a >= b),
-                Operator::Less => eval_comparison_operator(left,right,left_number,right_number,"<",|(a,b)| a < b),
-                Operator::LessEqual => eval_comparison_operator(left,right,left_number,right_number,"<=",|(a,b)| a <= b),
+                Operator::Greater => eval_comparison_operator(left_number,right_number,|(a,b)| a > b),
+                Operator::GreaterEqual => eval_comparison_operator(left_number,right_number,|(a,b)| a >= b),
+                Operator::Less => eval_comparison_operator(left_number,right_number,|(a,b)| a < b),
+                Operator::LessEqual => eval_comparison_operator(left_number,right_number,|(a,b)| a <= b),
                 // Arithmetic
-                Operator::Minus => eval_arithmetic_operator( left, right, left_number, right_number, "-", |(a, b)| a - b),
-                Operator::Plus => eval_arithmetic_operator( left, right, left_number, right_number, "+", |(a, b)| a + b),
-                Operator::Star => eval_arithmetic_operator( left, right, left_number, right_number, "*", |(a, b)| a * b),
-                Operator::Slash => eval_arithmetic_operator( left, right, left_number, right_number, "/", |(a, b)| a / b),
+                Operator::Minus => eval_arithmetic_operator(left_number, right_number, "-", |(a, b)| a - b),
+                Operator::Plus => eval_arithmetic_operator(left_number, right_number, "+", |(a, b)| a + b),
+                Operator::Star => eval_arithmetic_operator(left_number, right_number, "*", |(a, b)| a * b),
+                Operator::Slash => eval_arithmetic_operator(left_number, right_number, "/", |(a, b)| a / b),
                 _ => todo!(),
             }
         },
@@ -200,19 +615,30 @@ pub fn eval_expression(expr: &Expr, eval_state: Rc<RefCell<EvalState>>) -> EvalR
                 Operator::Or | Operator::And => {
                     eval_expression(right,Rc::clone(&eval_state))
                 },
-                _ => Err(RuntimeError(format!("Unexpected logical operator : {}", operator)))
+                _ => Err(RuntimeError::new(format!("Unexpected logical operator : {}", operator)))
             }
         },
         Grouping(expr) => eval_expression(expr,Rc::clone(&eval_state)),
-        Variable(id) => {
-          match eval_state.borrow().lookup(id) {
-              Ok(value) => Ok(value),
-              err @ Err(_) => err,
-          }
-        },
-        Assign(id, expr) => {
+        Variable(var) | This(var) => EvalState::lookup_var(&eval_state, var),
+        Assign(var, expr) => {
             let value = eval_expression(expr, Rc::clone(&eval_state))?;
-            eval_state.borrow_mut().assign(id,&value)?;
+            EvalState::assign_var(&eval_state, var, &value)?;
+            Ok(value)
+        },
+        Get(object, name) => {
+            match eval_expression(object, eval_state)? {
+                Value::Instance(instance) => eval_get_property(&instance, *name),
+                _ => Err(RuntimeError::new("Only instances have properties.")),
+            }
+        },
+        Super(var, method) => eval_super(var, *method, &eval_state),
+        Set(object, name, value) => {
+            let instance = match eval_expression(object, Rc::clone(&eval_state))? {
+                Value::Instance(instance) => instance,
+                _ => return Err(RuntimeError::new("Only instances have fields.")),
+            };
+            let value = eval_expression(value, eval_state)?;
+            instance.fields.borrow_mut().insert(*name, value.clone());
             Ok(value)
         },
     }
@@ -223,36 +649,158 @@ fn eval_call(
     arguments: &[Expr],
     eval_state: Rc<RefCell<EvalState>>,
 ) -> Result<Value, RuntimeError> {
-    let callee_evaluated = eval_expression(callee, Rc::clone(&eval_state));
+    let callee_evaluated = eval_expression(callee, Rc::clone(&eval_state))?;
 
-    // we don't know what a function is yet so just evaluate the arguments
-    // and package them up in a Call Value
-    let arguments_evaluated: Result<Vec<Value>, RuntimeError> = arguments
+    let arguments_evaluated = arguments
         .iter()
         .map(|arg_expr| eval_expression(arg_expr, Rc::clone(&eval_state)))
-        .collect();
+        .collect::<Result<Vec<Value>, RuntimeError>>()?;
+
+    call_value(callee_evaluated, arguments_evaluated, &eval_state)
+}
+
+/// call_value calls a function, native function or class with `arguments`, as
+/// a call expression in Lox would in the symbol table `eval_state`
+pub fn call_value(
+    callee: Value,
+    arguments: Vec<Value>,
+    eval_state: &Rc<RefCell<EvalState>>,
+) -> EvalResult {
+    match callee {
+        Value::Function(function) => call_function(&function, arguments),
+        Value::NativeFunction(native) => call_native(&native, arguments),
+        Value::Class(class) => call_class(&class, arguments, eval_state),
+        _ => Err(RuntimeError::new("Can only call functions and classes.")),
+    }
+}
+
+fn call_native(native: &NativeFunction, arguments: Vec<Value>) -> EvalResult {
+    if arguments.len() != native.arity {
+        return Err(RuntimeError::new(format!(
+            "Expected {} arguments but got {}.",
+            native.arity,
+            arguments.len()
+        )));
+    }
+    (native.function)(&arguments)
+}
+
+// Calling a class makes a new instance and runs the class's initializer, if it
+// has one, with the call's arguments
+fn call_class(
+    class: &Rc<LoxClass>,
+    arguments: Vec<Value>,
+    eval_state: &Rc<RefCell<EvalState>>,
+) -> EvalResult {
+    let instance = Rc::new(LoxInstance {
+        class: Rc::clone(class),
+        fields: RefCell::new(HashMap::new()),
+    });
+    if let Some(collector) = EvalState::collector(eval_state) {
+        collector.track_instance(&instance);
+    }
+    match class.find_method(Symbol::intern("init")) {
+        Some(initializer) => {
+            call_function(&initializer.bind(Rc::clone(&instance)), arguments)?;
+        }
+        None if !arguments.is_empty() => {
+            return Err(RuntimeError::new(format!(
+                "Expected 0 arguments but got {}.",
+                arguments.len()
+            )))
+        }
+        None => (),
+    }
+    Ok(Value::Instance(instance))
+}
+
+// super.method finds the method starting from the superclass and binds it to
+// `this`, which always lives in the scope just inside the one holding `super`
+fn eval_super(var: &VarRef, method: Symbol, eval_state: &Rc<RefCell<EvalState>>) -> EvalResult {
+    let superclass = match EvalState::lookup_var(eval_state, var)? {
+        Value::Class(class) => class,
+        _ => return Err(RuntimeError::new("Superclass must be a class.")),
+    };
+    let this_depth = var.depth.get().and_then(|depth| depth.checked_sub(1));
+    let instance = match this_depth {
+        Some(depth) => EvalState::ancestor(eval_state, depth)?.borrow().lookup(Symbol::intern("this"))?,
+        None => return Err(RuntimeError::new("Unresolved use of super")),
+    };
+    let instance = match instance {
+        Value::Instance(instance) => instance,
+        other => return Err(RuntimeError::new(format!("Expected an instance for this, not {}", other))),
+    };
+    match superclass.find_method(method) {
+        Some(method) => Ok(Value::Function(Rc::new(method.bind(instance)))),
+        None => Err(RuntimeError::new(format!("Undefined property '{}'.", method))),
+    }
+}
 
-    let value = Value::Callable(Box::new(callee_evaluated?), arguments_evaluated?);
-    Ok(value)
+// Fields shadow methods. Methods are bound to the instance they were accessed on.
+fn eval_get_property(instance: &Rc<LoxInstance>, name: Symbol) -> EvalResult {
+    if let Some(value) = instance.fields.borrow().get(&name) {
+        return Ok(value.clone());
+    }
+    match instance.class.find_method(name) {
+        Some(method) => Ok(Value::Function(Rc::new(method.bind(Rc::clone(instance))))),
+        None => Err(RuntimeError::new(format!("Undefined property '{}'.", name))),
+    }
+}
+
+// Functions run in a fresh symbol table, child of the function's closure, in
+// which each parameter is bound to its argument
+fn call_function(function: &LoxFunction, arguments: Vec<Value>) -> EvalResult {
+    let params = &function.declaration.params;
+    if arguments.len() != params.len() {
+        return Err(RuntimeError::new(format!(
+            "Expected {} arguments but got {}.",
+            params.len(),
+            arguments.len()
+        )));
+    }
+
+    let mut call_state = EvalState::new_from_parent(Rc::clone(&function.closure));
+    for (param, argument) in params.iter().zip(arguments) {
+        call_state.define(param.name, Some(argument));
+    }
+
+    let depth = CALL_DEPTH.get();
+    if depth >= MAX_CALL_DEPTH {
+        return Err(RuntimeError::new("Stack overflow."));
+    }
+    CALL_DEPTH.set(depth + 1);
+    let flow = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || {
+        execute_statements(&function.declaration.body, Rc::new(RefCell::new(call_state)))
+    });
+    CALL_DEPTH.set(depth);
+    let flow = flow?;
+    // An initializer always returns the instance, even when called directly
+    if function.is_initializer {
+        return function.closure.borrow().lookup(Symbol::intern("this"));
+    }
+    match flow {
+        Flow::Return(value) => Ok(value),
+        Flow::Next => Ok(Value::Nil),
+    }
 }
 
 // Nil is only equal to nil
 // Two numbers can be compared
 // Two bools can be compared
-// Otherwise it is not equal
-// TODO does it make sense to compare function objects?
+// Functions, classes and instances are equal only if they are the same object
+// Values of different types are never equal
 fn eval_equality_operator(left: Value, right: Value, negate: bool) -> EvalResult {
     let result = match (&left, &right) {
         (Value::Nil, Value::Nil) => true,
         (Value::Number(n1), Value::Number(n2)) => n1 == n2,
         (Value::Boolean(b1), Value::Boolean(b2)) => b1 == b2,
-        (Value::String(s1), Value::String(s2)) => s1 == s2,
-        _ => {
-            return Err(RuntimeError(format!(
-                "Don't know how to compare {:?} and {:?}",
-                left, right
-            )))
-        }
+        // Equal literals are interned to the same string
+        (Value::String(s1), Value::String(s2)) => Rc::ptr_eq(s1, s2) || s1 == s2,
+        (Value::Function(f1), Value::Function(f2)) => Rc::ptr_eq(f1, f2),
+        (Value::NativeFunction(f1), Value::NativeFunction(f2)) => Rc::ptr_eq(f1, f2),
+        (Value::Class(c1), Value::Class(c2)) => Rc::ptr_eq(c1, c2),
+        (Value::Instance(i1), Value::Instance(i2)) => Rc::ptr_eq(i1, i2),
+        _ => false,
     };
 
     if negate {
@@ -262,9 +810,8 @@ fn eval_equality_operator(left: Value, right: Value, negate: bool) -> EvalResult
     }
 }
 
+// Plus also works on strings, which its error message has to mention
 fn eval_arithmetic_operator<T>(
-    left: Value,
-    right: Value,
     left_number: Option<f64>,
     right_number: Option<f64>,
     text: &str,
@@ -275,19 +822,16 @@ where
 {
     match left_number.zip(right_number).map(f) {
         Some(result) => Ok(Value::Number(result)),
-        None => Err(RuntimeError(format!(
-            "Arithmetic error: {:?} {:?} {:?}",
-            left, text, right
-        ))),
+        None if text == "+" => Err(RuntimeError::new(
+            "Operands must be two numbers or two strings.",
+        )),
+        None => Err(RuntimeError::new("Operands must be numbers.")),
     }
 }
 
 fn eval_comparison_operator<T>(
-    left: Value,
-    right: Value,
     left_number: Option<f64>,
     right_number: Option<f64>,
-    text: &str,
     f: T,
 ) -> EvalResult
 where
@@ -295,16 +839,90 @@ where
 {
     match left_number.zip(right_number).map(f) {
         Some(result) => Ok(Value::Boolean(result)),
-        None => Err(RuntimeError(format!(
-            "Comparison error: {:?} {:?} {:?}",
-            left, text, right
-        ))),
+        None => Err(RuntimeError::new("Operands must be numbers.")),
     }
 }
 
 fn eval_string_append(left: Value, right: Value) -> EvalResult {
     match (&left, &right) {
-        (Value::String(s1), Value::String(s2)) => Ok(Value::String(format!("{}{}", s1, s2))),
-        _ => Err(RuntimeError(format!("Cannot string append {:?}", right))),
+        (Value::String(s1), Value::String(s2)) => Ok(Value::String(format!("{}{}", s1, s2).into())),
+        _ => Err(RuntimeError::new(
+            "Operands must be two numbers or two strings.",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+    use crate::resolve::resolve;
+    use crate::scan::scan;
+
+    fn run(source: &str, eval_state: EvalState) -> Result<(), RuntimeError> {
+        let stmts = parse(&scan(source).unwrap()).unwrap();
+        resolve(&stmts).unwrap();
+        eval_statements(&stmts, Rc::new(RefCell::new(eval_state)))
+    }
+
+    #[test]
+    fn test_register_native() {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let recorder = Rc::clone(&seen);
+        let mut eval_state = EvalState::new();
+        eval_state.register_native("record", 1, move |args| {
+            recorder.borrow_mut().push(args[0].to_string());
+            Ok(Value::Nil)
+        });
+
+        run("record(1 + 2); record(\"two\");", eval_state).unwrap();
+        assert_eq!(vec!["3", "two"], *seen.borrow());
+    }
+
+    #[test]
+    fn test_native_arity_and_errors() {
+        let mut eval_state = EvalState::new();
+        eval_state.register_native("fail", 0, |_| Err(RuntimeError::new("failed")));
+
+        assert!(run("clock(1);", EvalState::new()).is_err());
+        assert!(run("var t = clock();", EvalState::new()).is_ok());
+        assert_eq!("failed", run("fail();", eval_state).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_runtime_error_span() {
+        let error = run("var a = 1;\nprint a + nil;", EvalState::new()).unwrap_err();
+        assert_eq!(Some(Span::new(17, 7, 2, 7)), error.span());
+
+        let error = run("fun f() {\n  return -\"x\";\n}\nf();", EvalState::new()).unwrap_err();
+        assert_eq!(2, error.span().unwrap().line);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let recurse = |depth: usize| {
+            let source = format!(
+                "fun f(n) {{ if (n > 1) return f(n - 1); return n; }}\nf({});",
+                depth
+            );
+            run(&source, EvalState::new())
+        };
+
+        assert!(recurse(MAX_CALL_DEPTH).is_ok());
+        let error = recurse(MAX_CALL_DEPTH + 1).unwrap_err();
+        assert_eq!("Stack overflow.", error.message);
+        assert_eq!(1, error.span().unwrap().line);
+        // The depth is back to zero after the error
+        assert!(recurse(MAX_CALL_DEPTH).is_ok());
+        assert_eq!(0, CALL_DEPTH.get());
+    }
+
+    #[test]
+    fn test_print_to_output() {
+        let output = OutputBuffer::new();
+        let eval_state = EvalState::with_output(Box::new(output.clone()));
+
+        run("print 1 + 2;\nfun f() { print \"in f\"; }\nf();", eval_state).unwrap();
+        assert_eq!("3\nin f\n", output.contents());
     }
 }
@@ -0,0 +1,103 @@
+// Interned strings
+// Names are interned when they are parsed, so that looking up a variable,
+// field or method hashes and compares a small id rather than the whole name.
+// String literals are interned too, so that the tree walking interpreter can
+// compare equal ones by pointer. Interned strings are never freed, which is
+// fine for names and literals as there can only be as many as there are in the
+// source, so strings made while a program runs are not interned.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// An interned string. Each string is interned once, so two symbols are equal
+/// exactly when their ids are.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    strings: Vec<&'static str>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+    static LITERALS: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+/// intern_literal returns the one shared copy of a string literal, so that
+/// equal literals are the same Rc
+pub fn intern_literal(string: &str) -> Rc<str> {
+    LITERALS.with_borrow_mut(|literals| match literals.get(string) {
+        Some(literal) => Rc::clone(literal),
+        None => {
+            let literal: Rc<str> = string.into();
+            literals.insert(Rc::clone(&literal));
+            literal
+        }
+    })
+}
+
+impl Symbol {
+    /// intern returns the symbol of `string`, adding it if it is new
+    pub fn intern(string: &str) -> Symbol {
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            if let Some(symbol) = interner.ids.get(string) {
+                return *symbol;
+            }
+            let string: &'static str = Box::leak(string.into());
+            let symbol = Symbol(interner.strings.len() as u32);
+            interner.strings.push(string);
+            interner.ids.insert(string, symbol);
+            symbol
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().strings[self.0 as usize])
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Shown as the string, the id means nothing on its own
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::intern("name");
+        let b = Symbol::intern(&String::from("name"));
+        assert_eq!(a, b);
+        assert_ne!(a, Symbol::intern("other"));
+        assert_eq!("name", a.as_str());
+        assert!(a == "name");
+        assert_eq!("\"name\"", format!("{:?}", a));
+    }
+
+    #[test]
+    fn test_intern_literal() {
+        let a = intern_literal("lox");
+        assert!(Rc::ptr_eq(&a, &intern_literal(&String::from("lox"))));
+        assert!(!Rc::ptr_eq(&a, &intern_literal("other")));
+    }
+}
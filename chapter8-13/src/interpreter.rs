@@ -0,0 +1,288 @@
+// Embedding Lox
+// The Interpreter ties the scanner, parser, resolver and evaluator together
+// behind one type, so a program using this crate can run Lox source, read and
+// write its globals and call its functions without wiring up the stages itself.
+use crate::collect::{Collector, CycleStats};
+use crate::compile::CompileError;
+use crate::diagnostic::Diagnostic;
+use crate::eval::{call_value, eval_statements, EvalState, RuntimeError};
+use crate::intern::Symbol;
+use crate::parse::{parse, parse_line, ParseError, Stmt, Value};
+use crate::resolve::{resolve, ResolveError};
+use crate::scan::{scan_with_errors, ScanError};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Everything that can go wrong running Lox, keeping the errors of each stage
+/// with their messages and spans
+#[derive(Debug)]
+pub enum InterpreterError {
+    FileError(String, io::Error),
+    // Scanning carries on past errors, so the parser may find more of its own
+    SyntaxError(Vec<ScanError>, Vec<ParseError>),
+    ResolveError(Vec<ResolveError>),
+    // Only the bytecode compiler has limits that make it fail
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
+}
+
+impl From<Vec<ResolveError>> for InterpreterError {
+    fn from(errs: Vec<ResolveError>) -> Self {
+        InterpreterError::ResolveError(errs)
+    }
+}
+
+impl From<RuntimeError> for InterpreterError {
+    fn from(err: RuntimeError) -> Self {
+        InterpreterError::RuntimeError(err)
+    }
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpreterError::FileError(path, err) => write!(f, "Could not read {}: {}", path, err),
+            InterpreterError::SyntaxError(scan_errs, parse_errs) => {
+                let messages: Vec<String> = scan_errs
+                    .iter()
+                    .map(|err| err.to_string())
+                    .chain(parse_errs.iter().map(|err| err.to_string()))
+                    .collect();
+                write!(f, "Syntax Error: {}", messages.join("; "))
+            }
+            InterpreterError::ResolveError(errs) => {
+                let messages: Vec<String> = errs.iter().map(|err| err.to_string()).collect();
+                write!(f, "Resolve Error: {}", messages.join("; "))
+            }
+            InterpreterError::CompileError(errs) => {
+                let messages: Vec<String> = errs.iter().map(|err| err.to_string()).collect();
+                write!(f, "Compile Error: {}", messages.join("; "))
+            }
+            InterpreterError::RuntimeError(err) => write!(f, "Runtime Error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for InterpreterError {}
+
+impl InterpreterError {
+    /// exit_code is the process exit code the book uses for the error, taken
+    /// from BSD's sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InterpreterError::FileError(..) => 66,
+            InterpreterError::SyntaxError(..)
+            | InterpreterError::ResolveError(_)
+            | InterpreterError::CompileError(_) => 65,
+            InterpreterError::RuntimeError(_) => 70,
+        }
+    }
+
+    /// diagnostics are the errors in lox code, in source order, ready to be
+    /// rendered against the source. Other errors have no span.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            InterpreterError::SyntaxError(scan_errs, parse_errs) => {
+                let mut diagnostics: Vec<Diagnostic> = scan_errs
+                    .iter()
+                    .map(Diagnostic::from)
+                    .chain(parse_errs.iter().map(Diagnostic::from))
+                    .collect();
+                diagnostics.sort_by_key(|diagnostic| diagnostic.span.map(|span| span.offset));
+                diagnostics
+            }
+            InterpreterError::ResolveError(errs) => errs.iter().map(Diagnostic::from).collect(),
+            InterpreterError::CompileError(errs) => errs.iter().map(Diagnostic::from).collect(),
+            InterpreterError::RuntimeError(err) => vec![Diagnostic::from(err)],
+            other => vec![Diagnostic::new("error", other.to_string(), None)],
+        }
+    }
+}
+
+/// A Lox interpreter. Its globals last as long as it does, so each call to
+/// `run_source` sees what earlier ones declared.
+///
+/// ```
+/// use rlox::interpreter::Interpreter;
+/// use rlox::parse::Value;
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.run_source("fun double(n) { return n * 2; }").unwrap();
+/// let result = interpreter.call_function("double", vec![Value::Number(21.0)]);
+/// assert_eq!("42", result.unwrap().to_string());
+/// ```
+pub struct Interpreter {
+    globals: Rc<RefCell<EvalState>>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    /// new creates an interpreter that prints to stdout
+    pub fn new() -> Self {
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+    /// with_output creates an interpreter whose `print` writes to `output`
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        Interpreter {
+            globals: Rc::new(RefCell::new(EvalState::with_output(output))),
+        }
+    }
+    /// globals is the symbol table holding the global variables, for when more
+    /// control is needed than the other methods give
+    pub fn globals(&self) -> Rc<RefCell<EvalState>> {
+        Rc::clone(&self.globals)
+    }
+    /// register_native makes a Rust closure callable from Lox, see
+    /// `EvalState::register_native`
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        self.globals.borrow_mut().register_native(name, arity, function);
+    }
+    /// set_gc_stress makes this interpreter's cycle collector run whenever it
+    /// starts tracking an environment or instance
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.collector().set_stress(stress);
+    }
+    /// set_heap_growth_factor sets how much the objects the cycle collector
+    /// tracks may grow after a collection before the next one
+    pub fn set_heap_growth_factor(&mut self, growth_factor: f64) {
+        self.collector().set_growth_factor(growth_factor);
+    }
+    /// gc_stats counts the environments and instances this interpreter's cycle
+    /// collector tracked and freed
+    pub fn gc_stats(&self) -> CycleStats {
+        self.collector().stats()
+    }
+    // The globals made by `with_output` always have a collector
+    fn collector(&self) -> Rc<Collector> {
+        EvalState::collector(&self.globals).expect("globals own a collector")
+    }
+    /// compile scans, parses and resolves `source` ready to be executed,
+    /// collecting every error it finds on the way
+    pub fn compile(source: &str) -> Result<Vec<Stmt>, InterpreterError> {
+        let (tokens, scan_errs) = scan_with_errors(source);
+        resolve_parsed(scan_errs, parse(&tokens))
+    }
+    /// compile_line is compile for a line typed at the REPL, which may be a
+    /// single expression with no semicolon after it
+    pub fn compile_line(source: &str) -> Result<Vec<Stmt>, InterpreterError> {
+        let (tokens, scan_errs) = scan_with_errors(source);
+        resolve_parsed(scan_errs, parse_line(&tokens))
+    }
+    /// execute runs statements from `compile` in the globals
+    pub fn execute(&mut self, stmts: &[Stmt]) -> Result<(), InterpreterError> {
+        eval_statements(stmts, self.globals())?;
+        Ok(())
+    }
+    /// run_source compiles and executes some Lox source
+    pub fn run_source(&mut self, source: &str) -> Result<(), InterpreterError> {
+        let stmts = Interpreter::compile(source)?;
+        self.execute(&stmts)
+    }
+    /// run_file compiles and executes the Lox source in the file at `path`
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<(), InterpreterError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|err| InterpreterError::FileError(path.display().to_string(), err))?;
+        self.run_source(&source)
+    }
+    /// get_global is the value of the global variable `name`, if there is one
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().lookup(Symbol::intern(name)).ok()
+    }
+    /// set_global defines the global variable `name`, replacing any existing one
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().define(Symbol::intern(name), Some(value));
+    }
+    /// call_function calls the function or class in the global `name` with
+    /// `arguments` and gives back what it returns
+    pub fn call_function(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, InterpreterError> {
+        let callee = self.globals.borrow().lookup(Symbol::intern(name))?;
+        Ok(call_value(callee, arguments, &self.globals)?)
+    }
+}
+
+// Resolve the parsed statements, unless scanning or parsing found errors
+fn resolve_parsed(
+    scan_errs: Vec<ScanError>,
+    parsed: Result<Vec<Stmt>, Vec<ParseError>>,
+) -> Result<Vec<Stmt>, InterpreterError> {
+    let stmts = match parsed {
+        Ok(stmts) if scan_errs.is_empty() => stmts,
+        Ok(_) => return Err(InterpreterError::SyntaxError(scan_errs, vec![])),
+        Err(parse_errs) => return Err(InterpreterError::SyntaxError(scan_errs, parse_errs)),
+    };
+    resolve(&stmts)?;
+    Ok(stmts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::OutputBuffer;
+
+    #[test]
+    fn test_run_source_and_globals() {
+        let output = OutputBuffer::new();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+
+        interpreter.set_global("limit", Value::Number(3.0));
+        interpreter
+            .run_source("var total = 0; for (var i = 0; i < limit; i = i + 1) total = total + i;")
+            .unwrap();
+        interpreter.run_source("print total;").unwrap();
+        assert_eq!("3\n", output.contents());
+        assert_eq!("3", interpreter.get_global("total").unwrap().to_string());
+        assert!(interpreter.get_global("missing").is_none());
+    }
+
+    #[test]
+    fn test_call_function() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run_source("fun greet(name) { return \"hello \" + name; } class Point {}")
+            .unwrap();
+
+        let greeting = interpreter.call_function("greet", vec![Value::String("lox".into())]);
+        assert_eq!("hello lox", greeting.unwrap().to_string());
+        let point = interpreter.call_function("Point", vec![]).unwrap();
+        assert_eq!("Point instance", point.to_string());
+
+        let err = interpreter.call_function("greet", vec![]).unwrap_err();
+        assert_eq!("Expected 1 arguments but got 0.", err.diagnostics()[0].message);
+        let err = interpreter.call_function("nope", vec![]).unwrap_err();
+        assert_eq!("Undefined variable 'nope'.", err.diagnostics()[0].message);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut interpreter = Interpreter::new();
+        let err = interpreter.run_source("print 1 +;\nprint @;").unwrap_err();
+        assert_eq!(65, err.exit_code());
+        let messages: Vec<String> = err.diagnostics().into_iter().map(|d| d.message).collect();
+        assert_eq!(
+            vec!["Expect expression.", "Unexpected character.", "Expect expression."],
+            messages
+        );
+
+        let err = interpreter.run_source("return 1;").unwrap_err();
+        assert!(matches!(err, InterpreterError::ResolveError(_)));
+
+        let err = interpreter.run_source("-nil;").unwrap_err();
+        assert_eq!(70, err.exit_code());
+
+        let err = interpreter.run_file("no/such/file.lox").unwrap_err();
+        assert_eq!(66, err.exit_code());
+    }
+}
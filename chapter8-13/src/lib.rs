@@ -1,3 +1,13 @@
+pub mod chunk;
+pub mod collect;
+pub mod compile;
+pub mod debug;
+pub mod diagnostic;
 pub mod eval;
+pub mod intern;
+pub mod interpreter;
+pub mod object;
 pub mod parse;
+pub mod resolve;
 pub mod scan;
+pub mod vm;
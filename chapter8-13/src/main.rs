@@ -1,18 +1,22 @@
-use rlox::eval::eval_statements;
-use rlox::eval::EvalState;
-use rlox::eval::RuntimeError;
-use rlox::parse::parse;
+use rlox::debug::disassemble_program;
+use rlox::diagnostic::{render, render_book};
+use rlox::interpreter::{Interpreter, InterpreterError};
+use rlox::parse::parse_line;
 use rlox::parse::ParseError;
-use rlox::scan::scan;
+use rlox::parse::{Stmt, StmtKind};
+use rlox::scan::{scan, scan_with_errors};
 use rlox::scan::ScanError;
+use rlox::vm::Vm;
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
 use rustyline::history::DefaultHistory;
-use rustyline::Editor;
-use std::cell::RefCell;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
 use std::fs;
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use std::rc::Rc;
-use std::fmt;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -26,55 +30,122 @@ struct Opt {
     #[structopt(short = "e", long)]
     eval_enabled: Option<bool>,
 
+    /// Print the bytecode the virtual machine would run, whichever backend is used
+    #[structopt(long)]
+    dump_bytecode: bool,
+
+    /// Print the virtual machine's stack and each instruction as it is run.
+    /// This implies --backend vm.
+    #[structopt(long)]
+    trace: bool,
+
+    /// Collect garbage before every allocation, to find objects freed too soon
+    #[structopt(long)]
+    gc_stress: bool,
+
+    /// How much the heap may grow after a collection before the next one, as a
+    /// multiple of what survived it
+    #[structopt(long, default_value = "2")]
+    gc_growth_factor: f64,
+
+    /// Print how much was allocated and collected to stderr
+    #[structopt(long)]
+    gc_stats: bool,
+
+    /// Print the value of a program that is a single expression, as the REPL does
+    #[structopt(long)]
+    echo: bool,
+
+    /// How errors are printed: "book" is the format of the book's jlox, which its
+    /// test suite expects, and "pretty" shows the source that caused them. The
+    /// default, "auto", is pretty when stderr is a terminal and book otherwise.
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "book", "pretty"])]
+    error_format: String,
+
+    /// Print pretty errors without colour, also done when NO_COLOR is set or
+    /// stderr is not a terminal
+    #[structopt(long)]
+    no_color: bool,
+
+    /// Run programs by walking the syntax tree ("tree") or by compiling them to
+    /// bytecode for the virtual machine ("vm")
+    #[structopt(long, default_value = "tree", possible_values = &["tree", "vm"])]
+    backend: String,
+
     #[structopt(parse(from_os_str))]
     inputfile: Option<PathBuf>,
 }
 
-// Note: this error handling mechanism comes from https://fettblog.eu/rust-enums-wrapping-errors/
-// The idea is to make an enum that covers all application layer errors and then implement From
-// trait for each "real" error into the application level one here...
-
-#[derive(Debug)]
-enum InterpreterError {
-    FileNotFound(String),
-    ScanError(()),
-    ParseError(()),
-    RuntimeError(()),
-    ReadlineError(()),
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    Book,
+    Pretty { colour: bool },
 }
 
-impl From<ScanError> for InterpreterError {
-    fn from(_: ScanError) -> Self {
-        InterpreterError::ScanError(())
-    }
+/// What is printed on the way to running a program, and whether it is run
+#[derive(Clone, Copy)]
+struct Stages {
+    show_scan: bool,
+    show_parse: bool,
+    dump_bytecode: bool,
+    eval_enabled: bool,
 }
 
-impl From<ParseError> for InterpreterError {
-    fn from(_: ParseError) -> Self {
-        InterpreterError::ParseError(())
-    }
+/// The two ways of running a program, which should give the same results
+enum Backend {
+    Tree(Interpreter),
+    Vm(Box<Vm>),
 }
 
-impl From<RuntimeError> for InterpreterError {
-    fn from(_: RuntimeError) -> Self {
-        InterpreterError::RuntimeError(())
+impl Backend {
+    fn new(name: &str, trace: bool) -> Self {
+        match name {
+            _ if trace => {
+                let mut vm = Vm::new();
+                vm.set_trace(true);
+                Backend::Vm(Box::new(vm))
+            }
+            "vm" => Backend::Vm(Box::new(Vm::new())),
+            _ => Backend::Tree(Interpreter::new()),
+        }
     }
-}
 
-impl From<ReadlineError> for InterpreterError {
-    fn from(_: ReadlineError) -> Self {
-        InterpreterError::ReadlineError(())
+    fn set_gc(&mut self, stress: bool, growth_factor: f64) {
+        match self {
+            Backend::Tree(interpreter) => {
+                interpreter.set_gc_stress(stress);
+                interpreter.set_heap_growth_factor(growth_factor);
+            }
+            Backend::Vm(vm) => {
+                vm.set_gc_stress(stress);
+                vm.set_heap_growth_factor(growth_factor);
+            }
+        }
     }
-}
 
-impl fmt::Display for InterpreterError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fn print_gc_stats(&self) {
+        let stats = match self {
+            Backend::Tree(interpreter) => interpreter.gc_stats().to_string(),
+            Backend::Vm(vm) => vm.gc_stats().to_string(),
+        };
+        eprintln!("GC: {}", stats);
+    }
+
+    fn execute(&mut self, stmts: &[Stmt]) -> Result<(), InterpreterError> {
         match self {
-            InterpreterError::FileNotFound(path) => write!(f, "File not found: {}", path),
-            InterpreterError::ScanError(()) => write!(f, "Scan Error"),
-            InterpreterError::ParseError(()) => write!(f, "Parse Error"),
-            InterpreterError::RuntimeError(()) => write!(f, "Runtime Error"),
-            InterpreterError::ReadlineError(()) => write!(f, "REPL Error"),
+            Backend::Tree(interpreter) => interpreter.execute(stmts),
+            Backend::Vm(vm) => vm.interpret(stmts),
+        }
+    }
+}
+
+/// Print an error to stderr. Errors in lox code are shown with the part of
+/// `source`, which is called `name`, that caused them.
+fn report(err: &InterpreterError, source: &str, name: &str, format: ErrorFormat) {
+    for diagnostic in err.diagnostics() {
+        match format {
+            ErrorFormat::Book => eprint!("{}", render_book(&diagnostic, source)),
+            ErrorFormat::Pretty { colour } => eprint!("{}", render(&diagnostic, source, name, colour)),
         }
     }
 }
@@ -82,59 +153,145 @@ impl fmt::Display for InterpreterError {
 /// Load and interpret the lox file identified by the PathBuf f
 fn interpret_file(
     f: &PathBuf,
-    show_scan: bool,
-    show_parse: bool,
-    eval_enabled: bool,
+    stages: Stages,
+    echo: bool,
+    format: ErrorFormat,
+    backend: &mut Backend,
+) -> Result<(), InterpreterError> {
+    let name = f.to_string_lossy().to_string();
+    let source = fs::read_to_string(f).map_err(|err| InterpreterError::FileError(name.clone(), err))?;
+    interpret_source(&source, stages, echo, backend)
+        .inspect_err(|err| report(err, &source, &name, format))
+}
+
+/// Scan, parse, resolve and evaluate some lox source. Top level declarations
+/// go into the globals of `backend`, which may be shared between calls.
+/// With `echo_expression` set, source that is a single expression statement
+/// has its value printed, and the expression needs no semicolon.
+fn interpret_source(
+    source: &str,
+    stages: Stages,
+    echo_expression: bool,
+    backend: &mut Backend,
 ) -> Result<(), InterpreterError> {
-    let source = fs::read_to_string(f).map_err(|_err| {
-        InterpreterError::FileNotFound(f.clone().to_string_lossy().to_string())
-    })?;
-    let tokens = scan(&source)?;
+    let Stages {
+        show_scan,
+        show_parse,
+        dump_bytecode,
+        eval_enabled,
+    } = stages;
+    // Tokens and syntax trees are printed in the same format as the book
     if show_scan {
-        println!("Tokens:");
-        tokens.iter().for_each(|token| println!("\t{:?}", token));
+        let (tokens, scan_errs) = scan_with_errors(source);
+        tokens.iter().for_each(|token| println!("{:?}", token));
+        // With nothing else to do stop here, as the book's chapter 4 tests
+        // scan source that does not parse
+        if !show_parse && !dump_bytecode && !eval_enabled {
+            return match scan_errs.is_empty() {
+                true => Ok(()),
+                false => Err(InterpreterError::SyntaxError(scan_errs, vec![])),
+            };
+        }
+    }
+    let mut parsed = match echo_expression {
+        true => Interpreter::compile_line(source)?,
+        false => Interpreter::compile(source)?,
+    };
+    if echo_expression
+        && let [Stmt {
+            kind: StmtKind::Expression(_),
+            ..
+        }] = parsed.as_slice()
+        && let Some(Stmt {
+            kind: StmtKind::Expression(expr),
+            span,
+        }) = parsed.pop()
+    {
+        parsed.push(Stmt::new(StmtKind::Print(expr), span));
     }
-    let parsed = parse(&tokens)?;
     if show_parse {
-        println!("\nParsed AST:\n");
         for statement in &parsed {
-            println!("\t{}", statement)
+            match &statement.kind {
+                StmtKind::Expression(expr) => println!("{}", expr),
+                _ => println!("{}", statement),
+            }
         }
     }
+    if dump_bytecode {
+        let listing = disassemble_program(&parsed).map_err(InterpreterError::CompileError)?;
+        print!("{}", listing);
+    }
     if eval_enabled {
-        let eval_state = EvalState::new();
-        eval_statements(&parsed, Rc::new(RefCell::new(eval_state)))?;
+        backend.execute(&parsed)?;
     }
     Ok(())
 }
 
-fn repl(show_scan: bool, show_parse: bool, should_eval: bool) -> Result<(), InterpreterError> {
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<(), DefaultHistory>::new().unwrap();
+/// Editor helper that lets the REPL accept multi-line input. Pressing enter on
+/// input that stops part way through a statement, such as an unclosed `{` or
+/// string, starts a new line of the same entry instead of submitting it.
+struct LoxHelper;
+
+impl Validator for LoxHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let incomplete = match scan(ctx.input()) {
+            Err(errs) => errs
+                .iter()
+                .any(|err| {
+                    matches!(
+                        err,
+                        ScanError::UnterminatedString(_) | ScanError::UnterminatedComment(_)
+                    )
+                }),
+            Ok(tokens) => matches!(
+                parse_line(&tokens),
+                Err(errs) if errs.iter().all(ParseError::is_incomplete)
+            ),
+        };
+        if incomplete {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+/// The REPL keeps one set of globals for the whole session so that each line
+/// can use what earlier ones declared. Errors are reported and the session
+/// carries on, only interrupting (Ctrl-C) or ending input (Ctrl-D) stops it.
+fn repl(
+    stages: Stages,
+    format: ErrorFormat,
+    backend: &mut Backend,
+) -> Result<(), ReadlineError> {
+    let mut rl = Editor::<LoxHelper, DefaultHistory>::new().unwrap();
+    rl.set_helper(Some(LoxHelper));
     println!("Lox scanner");
     if rl.load_history("history.txt").is_err() {
         println!("No previous history.");
     }
     loop {
-        let line = rl.readline(">> ")?;
-        let tokens = scan(&line)?;
-        if show_scan {
-            println!("Tokens:");
-            tokens.iter().for_each(|token| println!("\t{:?}", token));
+        let line = match rl.readline(">> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let _ = rl.add_history_entry(line.as_str());
+        if let Err(err) = interpret_source(&line, stages, true, backend) {
+            report(&err, &line, "<repl>", format);
         }
-        let parsed = parse(&tokens)?;
-            let _ = rl.add_history_entry(line.as_str());
-            if show_parse {
-                println!("\nParsed AST:\n\n");
-                for statement in &parsed {
-                    println!("\t{}", statement)
-                }
-            }
-            if should_eval {
-                let eval_state = EvalState::new();
-                let eval_result = eval_statements(&parsed, Rc::new(RefCell::new(eval_state)));
-                println!("Eval result: {:?}", eval_result);
-            }
         rl.save_history("history.txt").unwrap();
     }
 }
@@ -145,14 +302,57 @@ fn main() {
         show_parse,
         inputfile,
         eval_enabled,
+        dump_bytecode,
+        trace,
+        gc_stress,
+        gc_growth_factor,
+        gc_stats,
+        echo,
+        error_format,
+        no_color,
+        backend,
     } = Opt::from_args();
 
-    let should_eval = eval_enabled.unwrap_or(true);
-
-    let result = match inputfile {
-        Some(f) => interpret_file(&f, show_scan, show_parse, should_eval),
-        None => repl(show_scan, show_parse, should_eval),
+    let stages = Stages {
+        show_scan,
+        show_parse,
+        dump_bytecode,
+        eval_enabled: eval_enabled.unwrap_or(true),
+    };
+    let is_terminal = std::io::stderr().is_terminal();
+    let format = match error_format.as_str() {
+        "book" => ErrorFormat::Book,
+        "auto" if !is_terminal => ErrorFormat::Book,
+        _ => ErrorFormat::Pretty {
+            colour: !no_color && std::env::var_os("NO_COLOR").is_none() && is_terminal,
+        },
     };
 
-    println!("Result: {:?}", result);
+    let mut backend = Backend::new(&backend, trace);
+    backend.set_gc(gc_stress, gc_growth_factor);
+    match inputfile {
+        Some(f) => {
+            let result = interpret_file(&f, stages, echo, format, &mut backend);
+            if gc_stats {
+                backend.print_gc_stats();
+            }
+            // Errors in the lox program have already been reported along with its source
+            if let Err(err) = result {
+                if let InterpreterError::FileError(..) = err {
+                    eprintln!("{}", err);
+                }
+                std::process::exit(err.exit_code());
+            }
+        }
+        None => {
+            let result = repl(stages, format, &mut backend);
+            if gc_stats {
+                backend.print_gc_stats();
+            }
+            if let Err(err) = result {
+                eprintln!("REPL Error: {}", err);
+                std::process::exit(74);
+            }
+        }
+    }
 }
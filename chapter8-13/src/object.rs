@@ -0,0 +1,440 @@
+// Values and heap objects of the bytecode virtual machine
+// Numbers, booleans and nil are stored directly in a Value, everything else
+// lives on the Heap and is referred to by an ObjRef handle. Objects refer to
+// each other by handle too, so the heap is the only owner of any of them and
+// frees them with a mark and sweep collection, like clox's memory.c, once they
+// can no longer be reached from the roots the virtual machine gives it.
+// Strings are interned, there is only ever one object for the same text, so
+// they are equal exactly when their handles are.
+use crate::chunk::{Chunk, OpCode};
+use crate::eval::NativeFn;
+use crate::parse;
+use crate::scan::Span;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of;
+use std::rc::Rc;
+
+// Collect for the first time once this many bytes are allocated
+const FIRST_GC: usize = 1024 * 1024;
+
+/// A handle to an object on the Heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+/// A compiled function. Once compiled it never changes, so the virtual
+/// machine can hold on to it while it runs the code.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Chunk,
+    // Where each of the closure's upvalues is captured from when it is created
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.as_str() {
+            "" => write!(f, "<script>"),
+            name => write!(f, "<fn {}>", name),
+        }
+    }
+}
+
+/// How a closure finds a variable it captures from the enclosing function,
+/// either in that function's stack slot `index` or its upvalue `index`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueRef {
+    pub index: u8,
+    pub is_local: bool,
+}
+
+/// A function implemented in Rust. It is the same closure as the tree walking
+/// interpreter's natives take, the virtual machine converts the arguments and
+/// result to and from the tree walking interpreter's values.
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure. It stays open, pointing at the stack slot
+/// of the variable, until the variable goes out of scope and it is closed over
+/// a copy of the value.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    // Keyed by the interned names of the methods
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    // Keyed by the interned names of the fields
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+/// A method taken from an instance, which remembers the instance as `this`
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    String(Rc<str>),
+    Function(Rc<Function>),
+    Native(Native),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+}
+
+impl Obj {
+    /// size is roughly how many bytes the object takes up, itself and what it
+    /// owns. It is worked out when the object is allocated and what is added
+    /// to it later, such as the fields of an instance, is not counted.
+    fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(string) => string.len(),
+            Obj::Function(function) => {
+                function.name.len()
+                    + function.chunk.code.len() * size_of::<OpCode>()
+                    + function.chunk.spans.len() * size_of::<Span>()
+                    + function.chunk.constants.len() * size_of::<Value>()
+                    + function.upvalues.len() * size_of::<UpvalueRef>()
+            }
+            Obj::Native(native) => native.name.len(),
+            Obj::Closure(closure) => closure.upvalues.len() * size_of::<ObjRef>(),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+            Obj::Class(class) => {
+                class.name.len() + class.methods.len() * size_of::<(ObjRef, ObjRef)>()
+            }
+            Obj::Instance(instance) => instance.fields.len() * size_of::<(ObjRef, Value)>(),
+        };
+        size_of::<Obj>() + owned
+    }
+
+    /// references are the values the object keeps alive. Functions keep their
+    /// constants alive, which include the names they use and the functions
+    /// declared in them.
+    pub fn references(&self) -> Vec<Value> {
+        match self {
+            Obj::String(_) | Obj::Native(_) => vec![],
+            Obj::Function(function) => function.chunk.constants.clone(),
+            Obj::Closure(closure) => {
+                let mut references = closure.function.chunk.constants.clone();
+                references.extend(closure.upvalues.iter().map(|upvalue| Value::Obj(*upvalue)));
+                references
+            }
+            Obj::Upvalue(Upvalue::Open(_)) => vec![],
+            Obj::Upvalue(Upvalue::Closed(value)) => vec![*value],
+            Obj::Class(class) => class
+                .methods
+                .iter()
+                .flat_map(|(name, method)| [Value::Obj(*name), Value::Obj(*method)])
+                .collect(),
+            Obj::Instance(instance) => {
+                let mut references = vec![Value::Obj(instance.class)];
+                for (name, value) in &instance.fields {
+                    references.extend([Value::Obj(*name), *value]);
+                }
+                references
+            }
+            Obj::BoundMethod(bound) => vec![bound.receiver, Value::Obj(bound.method)],
+        }
+    }
+}
+
+/// Counts kept by the heap of its allocations and collections
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} collections, {} bytes allocated, {} bytes freed",
+            self.collections, self.bytes_allocated, self.bytes_freed
+        )
+    }
+}
+
+// An object on the heap along with what the collector needs to know about it
+#[derive(Debug)]
+struct Entry {
+    obj: Obj,
+    size: usize,
+    marked: bool,
+}
+
+/// Where the objects live. Freed slots are reused by later allocations.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<Entry>>,
+    free: Vec<usize>,
+    // Every string on the heap. It doesn't keep them alive, strings that are
+    // collected are removed from it.
+    strings: HashMap<Rc<str>, ObjRef>,
+    // Bytes in the objects allocated now, and the number that triggers the
+    // next collection
+    bytes_in_use: usize,
+    next_gc: usize,
+    growth_factor: f64,
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: vec![],
+            free: vec![],
+            strings: HashMap::new(),
+            bytes_in_use: 0,
+            next_gc: FIRST_GC,
+            growth_factor: 2.0,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// alloc moves `obj` onto the heap and returns its handle. It never
+    /// collects, whoever allocates checks should_collect first as only they
+    /// know the roots. Strings must be new, see find_string.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let string = match &obj {
+            Obj::String(string) => Some(Rc::clone(string)),
+            _ => None,
+        };
+        let size = obj.size();
+        self.bytes_in_use += size;
+        self.stats.bytes_allocated += size;
+        let entry = Some(Entry {
+            obj,
+            size,
+            marked: false,
+        });
+        let obj = match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = entry;
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        };
+        if let Some(string) = string {
+            debug_assert!(!self.strings.contains_key(&string), "string allocated twice");
+            self.strings.insert(string, obj);
+        }
+        obj
+    }
+    /// find_string is the string object with the text `string`, if there is one
+    pub fn find_string(&self, string: &str) -> Option<ObjRef> {
+        self.strings.get(string).copied()
+    }
+    /// intern returns the string object with the text `string`, allocating
+    /// one if there isn't one already
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        match self.find_string(string) {
+            Some(obj) => obj,
+            None => self.alloc(Obj::String(string.into())),
+        }
+    }
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        &self.objects[obj.0].as_ref().expect("use of a freed object").obj
+    }
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        &mut self.objects[obj.0].as_mut().expect("use of a freed object").obj
+    }
+    /// set_growth_factor sets how much the heap may grow after a collection
+    /// before the next one, as a multiple of what survived it
+    pub fn set_growth_factor(&mut self, growth_factor: f64) {
+        self.growth_factor = growth_factor;
+    }
+    /// set_stress makes should_collect always true, to shake out objects that
+    /// are in use but not reachable from the roots
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+    /// should_collect is whether enough has been allocated since the last
+    /// collection to do another
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_in_use > self.next_gc
+    }
+    /// collect frees every object that can't be reached from `roots`
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        // Mark: the gray objects are marked but their references are not yet
+        let mut gray: Vec<ObjRef> = vec![];
+        for root in roots {
+            self.mark(root, &mut gray);
+        }
+        while let Some(obj) = gray.pop() {
+            for value in self.get(obj).references() {
+                self.mark(value, &mut gray);
+            }
+        }
+
+        // Sweep
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(entry) => {
+                    if let Obj::String(string) = &entry.obj {
+                        self.strings.remove(string);
+                    }
+                    self.bytes_in_use -= entry.size;
+                    self.stats.bytes_freed += entry.size;
+                    *slot = None;
+                    self.free.push(index);
+                }
+                None => {}
+            }
+        }
+        self.stats.collections += 1;
+        self.next_gc = ((self.bytes_in_use as f64 * self.growth_factor) as usize).max(FIRST_GC);
+    }
+    /// string is the text of `obj` if it is a string
+    pub fn string(&self, obj: ObjRef) -> Option<&str> {
+        match self.get(obj) {
+            Obj::String(string) => Some(string),
+            _ => None,
+        }
+    }
+    fn mark(&mut self, value: Value, gray: &mut Vec<ObjRef>) {
+        if let Value::Obj(obj) = value
+            && let Some(entry) = self.objects[obj.0].as_mut()
+            && !entry.marked
+        {
+            entry.marked = true;
+            gray.push(obj);
+        }
+    }
+    /// len is the number of objects currently allocated
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// display wraps `value` so it can be printed as Lox prints it
+    pub fn display(&self, value: Value) -> DisplayValue<'_> {
+        DisplayValue { heap: self, value }
+    }
+}
+
+/// A value together with the heap it refers to, which is needed to print it
+pub struct DisplayValue<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let obj = match self.value {
+            Value::Nil => return write!(f, "nil"),
+            Value::Boolean(b) => return write!(f, "{}", b),
+            // Numbers print the same as they do in the tree walking interpreter
+            Value::Number(n) => return write!(f, "{}", parse::Value::Number(n)),
+            Value::Obj(obj) => obj,
+        };
+        match self.heap.get(obj) {
+            Obj::String(string) => write!(f, "{}", string),
+            Obj::Function(function) => write!(f, "{}", function),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Closure(closure) => write!(f, "{}", closure.function),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => match self.heap.get(instance.class) {
+                Obj::Class(class) => write!(f, "{} instance", class.name),
+                _ => write!(f, "instance"),
+            },
+            Obj::BoundMethod(bound) => write!(f, "{}", self.heap.display(Value::Obj(bound.method))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let mut heap = Heap::new();
+        let name = heap.intern("a");
+        let class = heap.alloc(Obj::Class(Class {
+            name: "A".to_string(),
+            methods: HashMap::new(),
+        }));
+        let mut fields = HashMap::new();
+        fields.insert(heap.intern("name"), Value::Obj(name));
+        let instance = heap.alloc(Obj::Instance(Instance { class, fields }));
+        let garbage = heap.intern("garbage");
+        // Cycles are freed as well, unlike with Rc
+        let upvalue = heap.alloc(Obj::Upvalue(Upvalue::Open(0)));
+        *heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Closed(Value::Obj(upvalue)));
+        assert_eq!(6, heap.len());
+
+        heap.collect([Value::Obj(instance), Value::Number(1.0)]);
+        assert_eq!(4, heap.len());
+        assert_eq!(Some("a"), heap.string(name));
+        assert!(heap.objects[garbage.0].is_none());
+        // Collected strings are no longer interned
+        assert_eq!(Some(name), heap.find_string("a"));
+        assert_eq!(None, heap.find_string("garbage"));
+
+        let stats = heap.stats();
+        assert_eq!(1, stats.collections);
+        assert_eq!(2 * size_of::<Obj>() + "garbage".len(), stats.bytes_freed);
+        assert_eq!(stats.bytes_allocated - stats.bytes_freed, heap.bytes_in_use);
+
+        // The slots of freed objects are reused
+        let reused = heap.intern("b");
+        assert!(reused == garbage || reused == upvalue);
+    }
+}
@@ -1,18 +1,47 @@
-use crate::scan::{num_format, Token, TokenInstance};
+use crate::eval::{LoxClass, LoxFunction, LoxInstance, NativeFunction};
+use crate::intern::{intern_literal, Symbol};
+use crate::scan::{num_format, Span, Token, TokenInstance};
+use std::cell::Cell;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    String(String),
+    String(Rc<str>),
     Boolean(bool),
     Number(f64),
     Nil,
-    Callable(Box<Value>, Vec<Value>),
+    Function(Rc<LoxFunction>),
+    NativeFunction(Rc<NativeFunction>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
 }
 
 #[derive(Debug)]
-pub struct ParseError(String);
+pub struct ParseError {
+    message: String,
+    span: Span,
+    incomplete: bool,
+}
+
+impl ParseError {
+    /// span is the location of the token that caused the error
+    pub fn span(&self) -> Span {
+        self.span
+    }
+    /// is_incomplete is true when the parser ran out of tokens, meaning the input
+    /// was fine as far as it went and more of it may fix the error
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Operator {
@@ -53,60 +82,175 @@ impl Display for Operator {
     }
 }
 
+/// A function declaration. It is shared (via Rc) between the syntax tree and
+/// every function value created from it, so the body is never copied
 #[derive(Debug)]
-pub enum Stmt {
+pub struct FunctionDecl {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Stmt>,
+}
+
+/// The name given to a variable, function, parameter or class where it is
+/// declared
+#[derive(Debug)]
+pub struct Identifier {
+    pub name: Symbol,
+    pub span: Span,
+}
+
+impl Display for Identifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// A statement and where it is in the source
+#[derive(Debug)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+#[derive(Debug)]
+pub enum StmtKind {
     Block(Vec<Stmt>),
+    Class(Identifier, Option<VarRef>, Vec<Rc<FunctionDecl>>),
     Expression(Expr),
+    Function(Rc<FunctionDecl>),
     If(Expr, Vec<Stmt>, Vec<Stmt>),
     Print(Expr),
-    VarDecl(String, Option<Expr>),
+    Return(Option<Expr>),
+    VarDecl(Identifier, Option<Expr>),
     While(Expr, Vec<Stmt>),
 }
 
 impl Display for Stmt {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Stmt::Block(stmts) => {
+        match &self.kind {
+            StmtKind::Block(stmts) => {
                 write!(f, "{{")?;
                 for stmt in stmts {
                     write!(f, "{}", stmt)?;
                 }
                 write!(f, "}}")
             }
-            Stmt::VarDecl(ident, expr) => write!(f, "var {} = {:?};", ident, expr),
-            Stmt::Expression(expr) => write!(f, "{};", expr),
-            Stmt::Print(expr) => write!(f, "print {};", expr),
-            Stmt::If(cond, then_stmt, else_stmt) => {
+            StmtKind::Class(name, superclass, methods) => {
+                write!(f, "class {}", name)?;
+                if let Some(superclass) = superclass {
+                    write!(f, " < {}", superclass.name)?;
+                }
+                write!(f, " {{")?;
+                for method in methods {
+                    write!(f, "{}({})", method.name, join_names(&method.params))?;
+                }
+                write!(f, "}}")
+            }
+            StmtKind::VarDecl(ident, expr) => write!(f, "var {} = {:?};", ident, expr),
+            StmtKind::Expression(expr) => write!(f, "{};", expr),
+            StmtKind::Function(decl) => {
+                write!(f, "fun {}({}) {{", decl.name, join_names(&decl.params))?;
+                for stmt in &decl.body {
+                    write!(f, "{}", stmt)?;
+                }
+                write!(f, "}}")
+            }
+            StmtKind::Print(expr) => write!(f, "print {};", expr),
+            StmtKind::Return(Some(expr)) => write!(f, "return {};", expr),
+            StmtKind::Return(None) => write!(f, "return;"),
+            StmtKind::If(cond, then_stmt, else_stmt) => {
                 write!(f, "if {} then {:?} else {:?}", cond, then_stmt, else_stmt)
             }
-            Stmt::While(expr, stmt) => write!(f, "while {} {:?}", expr, stmt),
+            StmtKind::While(expr, stmt) => write!(f, "while {} {:?}", expr, stmt),
+        }
+    }
+}
+
+fn join_names(names: &[Identifier]) -> String {
+    let names: Vec<&str> = names.iter().map(|name| name.name.as_str()).collect();
+    names.join(", ")
+}
+
+/// A use of a variable by name. The resolver fills in `depth` with the number of
+/// scopes between the use and the variable's declaration, leaving it as None for
+/// globals
+#[derive(Debug)]
+pub struct VarRef {
+    pub name: Symbol,
+    pub span: Span,
+    pub depth: Cell<Option<usize>>,
+}
+
+impl VarRef {
+    pub fn new(name: Symbol, span: Span) -> Self {
+        VarRef {
+            name,
+            span,
+            depth: Cell::new(None),
         }
     }
 }
 
+/// An expression and where it is in the source
 #[derive(Debug)]
-pub enum Expr {
-    Assign(String, Box<Expr>),
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
+    Assign(VarRef, Box<Expr>),
     Binary(Box<Expr>, Operator, Box<Expr>),
     Call(Box<Expr>, Vec<Expr>),
+    Get(Box<Expr>, Symbol),
     Grouping(Box<Expr>),
+    Interpolation(Vec<Expr>),
     Literal(Value),
     Logical(Box<Expr>, Operator, Box<Expr>),
+    Set(Box<Expr>, Symbol, Box<Expr>),
+    Super(VarRef, Symbol),
+    This(VarRef),
     Unary(Operator, Box<Expr>),
-    Variable(String),
+    Variable(VarRef),
 }
 
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Expr::Assign(ident, expr) => write!(f, "(set {} {})", ident, expr),
-            Expr::Binary(l, operator, r) => write!(f, "({} {} {})", operator, l, r),
-            Expr::Call(callee, params) => write!(f, "(Call {} {:?})", callee, params),
-            Expr::Grouping(expr) => write!(f, "(grouping {})", expr),
-            Expr::Literal(literal) => write!(f, "{}", literal),
-            Expr::Logical(l, operator, r) => write!(f, "{} {} {}", l, operator, r),
-            Expr::Unary(operator, expr) => write!(f, "({} {})", operator, expr),
-            Expr::Variable(name) => write!(f, "{}", name),
+        match &self.kind {
+            ExprKind::Assign(var, expr) => write!(f, "(set {} {})", var.name, expr),
+            ExprKind::Binary(l, operator, r) => write!(f, "({} {} {})", operator, l, r),
+            ExprKind::Call(callee, params) => write!(f, "(Call {} {:?})", callee, params),
+            ExprKind::Get(object, name) => write!(f, "(get {} {})", object, name),
+            ExprKind::Grouping(expr) => write!(f, "(group {})", expr),
+            ExprKind::Interpolation(parts) => {
+                write!(f, "(interpolate")?;
+                for part in parts {
+                    write!(f, " {}", part)?;
+                }
+                write!(f, ")")
+            }
+            // Numbers keep their fraction, as in the book's AstPrinter
+            ExprKind::Literal(Value::Number(n)) => write!(f, "{}", num_format(*n)),
+            ExprKind::Literal(literal) => write!(f, "{}", literal),
+            ExprKind::Logical(l, operator, r) => write!(f, "{} {} {}", l, operator, r),
+            ExprKind::Set(object, name, value) => write!(f, "(set {} {} {})", object, name, value),
+            ExprKind::Super(_, method) => write!(f, "super.{}", method),
+            ExprKind::This(_) => write!(f, "this"),
+            ExprKind::Unary(operator, expr) => write!(f, "({} {})", operator, expr),
+            ExprKind::Variable(var) => write!(f, "{}", var.name),
         }
     }
 }
@@ -114,7 +258,10 @@ impl Display for Expr {
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Value::Number(n) => write!(f, "{}", num_format(*n)),
+            // Like the book, whole numbers are printed without a fraction
+            Value::Number(n) if n.is_infinite() && *n > 0.0 => write!(f, "Infinity"),
+            Value::Number(n) if n.is_infinite() => write!(f, "-Infinity"),
+            Value::Number(n) => write!(f, "{}", n),
             Value::String(string) => write!(f, "{}", string),
             Value::Boolean(b) => {
                 if *b {
@@ -124,7 +271,10 @@ impl Display for Value {
                 }
             }
             Value::Nil => write!(f, "nil"),
-            Value::Callable(callee, args) => write!(f, "Call some {} with {:?}", callee, args),
+            Value::Function(function) => write!(f, "<fn {}>", function.declaration.name),
+            Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
         }
     }
 }
@@ -134,26 +284,33 @@ impl Display for Value {
 struct ParseState<'a> {
     source: &'a [TokenInstance],
     current: usize,
+    errors: Vec<ParseError>,
 }
 
 // Grammar
 //
 // program -> block* EOF ;
 // block -> "{" declaration* "}" ;
-// declaration -> varDecl | statement ;
+// declaration -> classDecl | funDecl | varDecl | statement ;
+// classDecl -> "class" IDENTIFIER ( "<" IDENTIFIER )? "{" function* "}" ;
+// funDecl -> "fun" function ;
+// function -> IDENTIFIER "(" parameters? ")" "{" declaration* "}" ;
+// parameters -> IDENTIFIER ( "," IDENTIFIER )* ;
 // varDelc -> "var" IDENTIFIER ( "=" expression )? ";" ;
 
-// statement -> exprStatement | printStatement | ifStatement | whileStatement | forStatement | block ;
+// statement -> exprStatement | printStatement | ifStatement | whileStatement | forStatement
+//   | returnStatement | "{" declaration* "}" ;
+// returnStatement -> "return" expression? ";" ;
 // forStatement -> "for" "(" ( varDecl | exprStmt | ";" )
 //   expression? ";"
 //   expression? ")" statement ;
 // whileStatement -> "while" "(" expression ")" statement ;
 // exprStatement -> expression ";" ;
 // printStatement -> print expression ";" ;
-// ifStatement -> "if" "(" expression ")" ( "else" expression )? ;
+// ifStatement -> "if" "(" expression ")" statement ( "else" statement )? ;
 
 // expression -> assignment ;
-// assignment -> IDENTIFIER "=" assignment | logic_or;
+// assignment -> ( call "." )? IDENTIFIER "=" assignment | logic_or;
 // logic_or -> logic_and ( "or" logic_and )* ;
 // logic_and -> equality  ( "and" equality ) ;
 // equality -> comparison ( ( "!=" | "==" ) ) comparison )* ;
@@ -161,90 +318,209 @@ struct ParseState<'a> {
 // term -> factor ( ( "-" | "+" ) ) factor )* ;
 // factor -> unary ( ( "/" | "*" ) ) unary )* ;
 // unary -> ( "!" | "-" ) unary | call ;
-// call -> primary ( "(" arguments? ")" )* ;
+// primary -> "true" | "false" | "nil" | "this" | NUMBER | STRING | IDENTIFIER
+//   | "(" expression ")" | "super" "." IDENTIFIER | interpolation ;
+// interpolation -> ( INTERPOLATION expression )+ STRING ;
+// call -> primary ( "(" arguments? ")" | "." IDENTIFIER )* ;
 // arguments -> expression ( "," expression )* ;
 
 type ParseExprResult = Result<Expr, ParseError>;
 
-pub fn parse(input: &[TokenInstance]) -> Result<Vec<Stmt>, ParseError> {
+/// Parse a whole program. Parsing carries on after an error so that every error
+/// in the program is returned together rather than just the first.
+pub fn parse(input: &[TokenInstance]) -> Result<Vec<Stmt>, Vec<ParseError>> {
     let mut ps = ParseState {
         source: input,
         current: 0,
+        errors: vec![],
     };
 
     let mut statements = vec![];
     while !is_at_end(&ps) {
-        let statement = parse_block(&mut ps)?;
-        statements.push(statement);
+        if let Some(statement) = parse_recovering(&mut ps) {
+            statements.push(statement);
+        }
+    }
+    if ps.errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(ps.errors)
+    }
+}
+
+/// Parse a line typed at the REPL, which may be a single expression without
+/// the semicolon an expression statement needs so that its value can be echoed
+pub fn parse_line(input: &[TokenInstance]) -> Result<Vec<Stmt>, Vec<ParseError>> {
+    let mut ps = ParseState {
+        source: input,
+        current: 0,
+        errors: vec![],
+    };
+
+    match parse_expression(&mut ps) {
+        Ok(expr) if is_at_end(&ps) && ps.errors.is_empty() => {
+            let span = expr.span;
+            Ok(vec![Stmt::new(StmtKind::Expression(expr), span)])
+        }
+        _ => parse(input),
+    }
+}
+
+// Parse a declaration or block. On an error it is recorded and the parser skips
+// ahead to where the next statement probably starts (panic mode recovery).
+fn parse_recovering(ps: &mut ParseState) -> Option<Stmt> {
+    let start = ps.current;
+    match parse_block(ps) {
+        Ok(statement) => Some(statement),
+        Err(err) => {
+            // Errors at the end of input are seen by each enclosing block, only
+            // report them once
+            if ps.errors.last().map(|last| last.span) != Some(err.span) {
+                ps.errors.push(err);
+            }
+            synchronize(ps, start);
+            None
+        }
     }
-    Ok(statements)
 }
 
 fn parse_block(ps: &mut ParseState) -> Result<Stmt, ParseError> {
-    let mut statements = vec![];
     match peek(ps).token_type.clone() {
         Token::LeftBrace => {
-            advance(ps);
-            loop {
-                match peek(ps).token_type.clone() {
-                    Token::RightBrace => {
-                        advance(ps);
-                        return Ok(Stmt::Block(statements));
-                    }
-                    Token::Eof => {
-                        return Err(ParseError(
-                            "Expected }} but reached end of input".to_string(),
-                        ))
-                    }
-                    _ => {
-                        let stmt = parse_block(ps)?;
-                        statements.push(stmt)
-                    }
+            let start = advance(ps).span;
+            let stmts = parse_block_body(ps)?;
+            Ok(Stmt::new(StmtKind::Block(stmts), span_from(ps, start)))
+        }
+        _ => parse_declaration(ps),
+    }
+}
+
+// Parses the statements of a block up to and including the closing brace. The
+// opening brace has already been consumed.
+fn parse_block_body(ps: &mut ParseState) -> Result<Vec<Stmt>, ParseError> {
+    let mut statements = vec![];
+    loop {
+        match peek(ps).token_type.clone() {
+            Token::RightBrace => {
+                advance(ps);
+                return Ok(statements);
+            }
+            Token::Eof => return Err(error_at(peek(ps), "Expect '}' after block.")),
+            _ => {
+                if let Some(stmt) = parse_recovering(ps) {
+                    statements.push(stmt)
                 }
             }
         }
-        _ => parse_declaration(ps),
     }
 }
 
 fn parse_declaration(ps: &mut ParseState) -> Result<Stmt, ParseError> {
     match peek(ps).token_type.clone() {
+        Token::Class => {
+            advance(ps);
+            parse_class(ps)
+        }
+        Token::Fun => {
+            let start = advance(ps).span;
+            let function = parse_function(ps, "function")?;
+            Ok(Stmt::new(StmtKind::Function(function), span_from(ps, start)))
+        }
         Token::Var => {
             advance(ps);
-            let token = advance(ps).token_type.clone();
-            match token {
-                Token::Identifier(ident) => match advance(ps).token_type.clone() {
-                    Token::Equal => {
-                        let expr = parse_expression(ps)?;
-                        match advance(ps).token_type.clone() {
-                            Token::Semicolon | Token::Eof => Ok(Stmt::VarDecl(ident, Some(expr))),
-                            token => Err(ParseError(format!(
-                                "Unexpected token when parsing declaration: {}",
-                                token
-                            ))),
-                        }
-                    }
-                    Token::Semicolon | Token::Eof => Ok(Stmt::VarDecl(ident, None)),
-                    token => Err(ParseError(format!(
-                        "Unexpected token when parsing declaration: {}",
-                        token
-                    ))),
-                },
-                thing => return Err(ParseError(format!("Expected identifier, got {}", thing))),
+            parse_var_declaration(ps)
+        }
+        _ => parse_statement(ps),
+    }
+}
+
+fn parse_var_declaration(ps: &mut ParseState) -> Result<Stmt, ParseError> {
+    let start = previous(ps).span;
+    let ident = expect_identifier(ps, "Expect variable name.")?;
+    let initializer = match peek(ps).token_type {
+        Token::Equal => {
+            advance(ps);
+            Some(parse_expression(ps)?)
+        }
+        _ => None,
+    };
+    expect(ps, Token::Semicolon, "Expect ';' after variable declaration.")?;
+    Ok(Stmt::new(
+        StmtKind::VarDecl(ident, initializer),
+        span_from(ps, start),
+    ))
+}
+
+fn parse_class(ps: &mut ParseState) -> Result<Stmt, ParseError> {
+    let start = previous(ps).span;
+    let name = expect_identifier(ps, "Expect class name.")?;
+    let superclass = match peek(ps).token_type {
+        Token::Less => {
+            advance(ps);
+            let superclass = expect_identifier(ps, "Expect superclass name.")?;
+            Some(VarRef::new(superclass.name, superclass.span))
+        }
+        _ => None,
+    };
+    expect(ps, Token::LeftBrace, "Expect '{' before class body.")?;
+    let mut methods = vec![];
+    loop {
+        match peek(ps).token_type {
+            Token::RightBrace => {
+                advance(ps);
+                return Ok(Stmt::new(
+                    StmtKind::Class(name, superclass, methods),
+                    span_from(ps, start),
+                ));
+            }
+            Token::Eof => return Err(error_at(peek(ps), "Expect '}' after class body.")),
+            _ => methods.push(parse_function(ps, "method")?),
+        }
+    }
+}
+
+// Parses the name, parameters and body of a function or method, the `fun`
+// keyword (if any) has already been consumed. `kind` says which it is in errors.
+fn parse_function(ps: &mut ParseState, kind: &str) -> Result<Rc<FunctionDecl>, ParseError> {
+    let name = expect_identifier(ps, &format!("Expect {} name.", kind))?;
+    expect(ps, Token::LeftParen, &format!("Expect '(' after {} name.", kind))?;
+    let mut params = vec![];
+    if peek(ps).token_type != Token::RightParen {
+        loop {
+            if params.len() >= MAX_ARGUMENTS {
+                report(ps, error_at(peek(ps), "Can't have more than 255 parameters."));
+            }
+            params.push(expect_identifier(ps, "Expect parameter name.")?);
+            if peek(ps).token_type == Token::Comma {
+                advance(ps);
+            } else {
+                break;
             }
         }
+    }
+    expect(ps, Token::RightParen, "Expect ')' after parameters.")?;
+    expect(ps, Token::LeftBrace, &format!("Expect '{{' before {} body.", kind))?;
+    let body = parse_block_body(ps)?;
+    Ok(Rc::new(FunctionDecl { name, params, body }))
+}
+
+// The body of an if, while or for is a statement or block but not a declaration
+fn parse_body(ps: &mut ParseState) -> Result<Stmt, ParseError> {
+    match peek(ps).token_type {
+        Token::LeftBrace => parse_block(ps),
         _ => parse_statement(ps),
     }
 }
 
 fn parse_statement(ps: &mut ParseState) -> Result<Stmt, ParseError> {
     let peeked = peek(ps);
+    let start = peeked.span;
 
-    let response = match peeked.token_type {
+    let (response, terminator_message) = match peeked.token_type {
         Token::Print => {
             advance(ps);
             let expr = parse_expression(ps)?;
-            Stmt::Print(expr)
+            (StmtKind::Print(expr), "Expect ';' after value.")
         }
         Token::For => {
             advance(ps);
@@ -258,109 +534,124 @@ fn parse_statement(ps: &mut ParseState) -> Result<Stmt, ParseError> {
             advance(ps);
             return parse_if(ps);
         }
-        _ => Stmt::Expression(parse_expression(ps)?),
+        Token::Return => {
+            advance(ps);
+            let value = match peek(ps).token_type {
+                Token::Semicolon | Token::Eof => None,
+                _ => Some(parse_expression(ps)?),
+            };
+            (StmtKind::Return(value), "Expect ';' after return value.")
+        }
+        _ => (
+            StmtKind::Expression(parse_expression(ps)?),
+            "Expect ';' after expression.",
+        ),
     };
 
-    match &advance(ps).token_type {
-        Token::Semicolon | Token::Eof => Ok(response),
-        token => Err(ParseError(format!(
-            "Unexpected token when parsing statement: {}",
-            token
-        ))),
-    }
+    expect(ps, Token::Semicolon, terminator_message)?;
+    Ok(Stmt::new(response, span_from(ps, start)))
 }
 
 fn parse_while(ps: &mut ParseState) -> Result<Stmt, ParseError> {
-    expect(ps, Token::LeftParen)?;
+    let start = previous(ps).span;
+    expect(ps, Token::LeftParen, "Expect '(' after 'while'.")?;
     let cond = parse_expression(ps)?;
-    expect(ps, Token::RightParen)?;
-    let stmt = parse_block(ps)?;
-    Ok(Stmt::While(cond, vec![stmt]))
+    expect(ps, Token::RightParen, "Expect ')' after condition.")?;
+    let stmt = parse_body(ps)?;
+    Ok(Stmt::new(
+        StmtKind::While(cond, vec![stmt]),
+        span_from(ps, start),
+    ))
 }
 
+// The for loop is desugared into a while loop, every part of which is given the
+// span of the whole for statement
 fn parse_for(ps: &mut ParseState) -> Result<Stmt, ParseError> {
-    expect(ps, Token::LeftParen)?;
+    let start = previous(ps).span;
+    expect(ps, Token::LeftParen, "Expect '(' after 'for'.")?;
     let initializer = match peek(ps).token_type.clone() {
         Token::Semicolon => {
             advance(ps);
             None
         }
-        Token::Var => Some(parse_declaration(ps)?),
+        Token::Var => {
+            advance(ps);
+            Some(parse_var_declaration(ps)?)
+        }
         _ => Some(parse_statement(ps)?),
     };
     let condition = match peek(ps).token_type.clone() {
-        Token::Semicolon => {
-            advance(ps);
-            Expr::Literal(Value::Boolean(true))
-        }
+        Token::Semicolon => Expr::new(ExprKind::Literal(Value::Boolean(true)), peek(ps).span),
         _ => parse_expression(ps)?,
     };
-    expect(ps, Token::Semicolon)?;
+    expect(ps, Token::Semicolon, "Expect ';' after loop condition.")?;
     let increment = match peek(ps).token_type.clone() {
-        Token::RightParen => {
-            advance(ps);
-            None
-        }
-        _ => {
-            let expr = parse_expression(ps)?;
-            expect(ps, Token::RightParen)?;
-            Some(expr)
-        }
+        Token::RightParen => None,
+        _ => Some(parse_expression(ps)?),
     };
-    let mut while_body_stmts: Vec<Stmt> = vec![parse_block(ps)?];
+    expect(ps, Token::RightParen, "Expect ')' after for clauses.")?;
+    let mut while_body_stmts: Vec<Stmt> = vec![parse_body(ps)?];
+    let span = span_from(ps, start);
 
     if let Some(inc) = increment {
-        while_body_stmts.push(Stmt::Expression(inc))
+        let inc_span = inc.span;
+        while_body_stmts.push(Stmt::new(StmtKind::Expression(inc), inc_span))
     }
 
-    let body = Stmt::While(condition, while_body_stmts);
+    let body = Stmt::new(StmtKind::While(condition, while_body_stmts), span);
 
     if let Some(init) = initializer {
-        Ok(Stmt::Block(vec![init, body]))
+        Ok(Stmt::new(StmtKind::Block(vec![init, body]), span))
     } else {
         Ok(body)
     }
 }
 
 fn parse_if(ps: &mut ParseState) -> Result<Stmt, ParseError> {
-    expect(ps, Token::LeftParen)?;
+    let start = previous(ps).span;
+    expect(ps, Token::LeftParen, "Expect '(' after 'if'.")?;
     let cond = parse_expression(ps)?;
-    expect(ps, Token::RightParen)?;
-    let then_stmt = parse_block(ps)?;
+    expect(ps, Token::RightParen, "Expect ')' after if condition.")?;
+    let then_stmt = parse_body(ps)?;
 
-    match peek(ps).token_type.clone() {
+    let kind = match peek(ps).token_type.clone() {
         Token::Else => {
             advance(ps);
-            let else_stmt = parse_block(ps)?;
-            Ok(Stmt::If(cond, vec![then_stmt], vec![else_stmt]))
+            let else_stmt = parse_body(ps)?;
+            StmtKind::If(cond, vec![then_stmt], vec![else_stmt])
         }
-        _ => Ok(Stmt::If(cond, vec![then_stmt], vec![])),
-    }
+        _ => StmtKind::If(cond, vec![then_stmt], vec![]),
+    };
+    Ok(Stmt::new(kind, span_from(ps, start)))
 }
 
 fn parse_expression(ps: &mut ParseState) -> ParseExprResult {
     parse_assignment(ps)
 }
 
+// An invalid assignment target is reported without stopping the parse, as the
+// parser is not confused about where it is
 fn parse_assignment(ps: &mut ParseState) -> ParseExprResult {
     let expr = parse_or(ps)?;
 
     match peek(ps).token_type.clone() {
         Token::Equal => {
-            advance(ps);
+            let equals = advance(ps);
             let value = parse_assignment(ps)?;
+            let span = expr.span.to(value.span);
 
-            let name = match expr {
-                Expr::Variable(name) => name,
+            match expr.kind {
+                ExprKind::Variable(var) => {
+                    Ok(Expr::new(ExprKind::Assign(var, Box::new(value)), span))
+                }
+                ExprKind::Get(object, name) => {
+                    Ok(Expr::new(ExprKind::Set(object, name, Box::new(value)), span))
+                }
                 _ => {
-                    return Err(ParseError(format!(
-                        "Tried to assign to not a variable: {}",
-                        expr
-                    )))
+                    report(ps, error_at(equals, "Invalid assignment target."));
+                    Ok(expr)
                 }
-            };
-
-            Ok(Expr::Assign(name, Box::new(value)))
+            }
         }
         _ => Ok(expr),
     }
@@ -381,7 +672,7 @@ fn parse_equality(ps: &mut ParseState) -> ParseExprResult {
             Some(operator) => {
                 advance(ps);
                 let right = parse_comparison(ps)?;
-                expr = Expr::Binary(Box::new(expr), operator, Box::new(right))
+                expr = binary(ExprKind::Binary, expr, operator, right)
             }
             None => return Ok(expr),
         }
@@ -396,7 +687,7 @@ fn parse_or(ps: &mut ParseState) -> ParseExprResult {
             Token::Or => {
                 advance(ps);
                 let right = parse_and(ps)?;
-                expr = Expr::Logical(Box::new(expr), Operator::Or, Box::new(right));
+                expr = binary(ExprKind::Logical, expr, Operator::Or, right);
             }
             _ => return Ok(expr),
         }
@@ -411,7 +702,7 @@ fn parse_and(ps: &mut ParseState) -> ParseExprResult {
             Token::And => {
                 advance(ps);
                 let right = parse_equality(ps)?;
-                expr = Expr::Logical(Box::new(expr), Operator::And, Box::new(right));
+                expr = binary(ExprKind::Logical, expr, Operator::And, right);
             }
             _ => return Ok(expr),
         }
@@ -433,7 +724,7 @@ fn parse_comparison(ps: &mut ParseState) -> ParseExprResult {
             Some(operator) => {
                 advance(ps);
                 let right = parse_term(ps)?;
-                expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+                expr = binary(ExprKind::Binary, expr, operator, right);
             }
             None => return Ok(expr),
         }
@@ -453,7 +744,7 @@ fn parse_term(ps: &mut ParseState) -> ParseExprResult {
             Some(operator) => {
                 advance(ps);
                 let right = parse_factor(ps)?;
-                expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+                expr = binary(ExprKind::Binary, expr, operator, right);
             }
             None => return Ok(expr),
         }
@@ -473,7 +764,7 @@ fn parse_factor(ps: &mut ParseState) -> ParseExprResult {
             Some(operator) => {
                 advance(ps);
                 let right = parse_unary(ps)?;
-                expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+                expr = binary(ExprKind::Binary, expr, operator, right);
             }
             None => return Ok(expr),
         }
@@ -493,7 +784,8 @@ fn parse_unary(ps: &mut ParseState) -> ParseExprResult {
         Some(uo) => {
             advance(ps); // Need to advance since we peeked before only
             let unary = parse_unary(ps)?;
-            Ok(Expr::Unary(uo, Box::new(unary)))
+            let span = token.span.to(unary.span);
+            Ok(Expr::new(ExprKind::Unary(uo, Box::new(unary)), span))
         }
         None => parse_call(ps),
     }
@@ -504,15 +796,22 @@ fn parse_call(ps: &mut ParseState) -> ParseExprResult {
 
     loop {
         let token = peek(ps);
-        if token.token_type == Token::LeftParen {
-            advance(ps);
-            callee_expr = parse_finish_call(ps, callee_expr)?;
-        } else {
-            break;
+        match token.token_type {
+            Token::LeftParen => {
+                advance(ps);
+                callee_expr = parse_finish_call(ps, callee_expr)?;
+            }
+            Token::Dot => {
+                advance(ps);
+                let name = expect_identifier(ps, "Expect property name after '.'.")?;
+                let span = span_from(ps, callee_expr.span);
+                callee_expr = Expr::new(ExprKind::Get(Box::new(callee_expr), name.name), span);
+            }
+            _ => break,
         }
     }
 
-    return Ok(callee_expr);
+    Ok(callee_expr)
 }
 
 fn parse_finish_call(ps: &mut ParseState, callee: Expr) -> ParseExprResult {
@@ -521,6 +820,9 @@ fn parse_finish_call(ps: &mut ParseState, callee: Expr) -> ParseExprResult {
 
     if token.token_type != Token::RightParen {
         loop {
+            if arguments.len() >= MAX_ARGUMENTS {
+                report(ps, error_at(peek(ps), "Can't have more than 255 arguments."));
+            }
             arguments.push(parse_expression(ps)?);
             let token = peek(ps);
             if token.token_type == Token::Comma {
@@ -530,45 +832,133 @@ fn parse_finish_call(ps: &mut ParseState, callee: Expr) -> ParseExprResult {
             }
         }
     }
-    expect(ps, Token::RightParen)?;
-    return Ok(Expr::Call(Box::new(callee), arguments));
+    expect(ps, Token::RightParen, "Expect ')' after arguments.")?;
+    let span = span_from(ps, callee.span);
+    Ok(Expr::new(ExprKind::Call(Box::new(callee), arguments), span))
 }
 
 // This is for when a primary finds a left paren. Parse an expression and expect
 // a right paren.
 fn parse_group(ps: &mut ParseState) -> ParseExprResult {
+    let start = previous(ps).span;
     let expr = parse_expression(ps)?;
-    let token = advance(ps);
+    expect(ps, Token::RightParen, "Expect ')' after expression.")?;
+    Ok(Expr::new(
+        ExprKind::Grouping(Box::new(expr)),
+        span_from(ps, start),
+    ))
+}
 
-    match token.token_type {
-        Token::RightParen => Ok(Expr::Grouping(Box::new(expr))),
-        _ => Err(ParseError(format!(
-            "Failed finding matching right paren {:?} {}",
-            token, token.line
-        ))),
+// This is for when a primary finds the first segment of an interpolated string.
+// The segments of text and the expressions between them are its parts, in
+// order, leaving out empty segments.
+fn parse_interpolation(ps: &mut ParseState) -> ParseExprResult {
+    let first = previous(ps);
+    let mut parts = vec![];
+    loop {
+        let segment = previous(ps);
+        let (Token::Interpolation(text) | Token::String(text)) = &segment.token_type else {
+            unreachable!("interpolations are made of string segments");
+        };
+        if !text.is_empty() {
+            parts.push(Expr::new(
+                ExprKind::Literal(Value::String(intern_literal(text))),
+                segment.span,
+            ));
+        }
+        if let Token::String(_) = segment.token_type {
+            break;
+        }
+        parts.push(parse_expression(ps)?);
+        let next = peek(ps);
+        if !continues_interpolation(next) {
+            return Err(error_at(next, "Expect '}' after expression."));
+        }
+        advance(ps);
     }
+    // The virtual machine joins the parts with a single instruction
+    if parts.len() > MAX_ARGUMENTS {
+        report(ps, error_at(first, "Can't have more than 255 parts in a string."));
+    }
+    Ok(Expr::new(ExprKind::Interpolation(parts), span_from(ps, first.span)))
+}
+
+// Segments of a string after an interpolated expression start with the brace
+// that ended it
+fn continues_interpolation(token: &TokenInstance) -> bool {
+    matches!(token.token_type, Token::Interpolation(_) | Token::String(_))
+        && token.lexeme.starts_with('}')
 }
 
 fn parse_primary(ps: &mut ParseState) -> ParseExprResult {
     let token = advance(ps);
-
-    match &token.token_type {
-        Token::True => Ok(Expr::Literal(Value::Boolean(true))),
-        Token::False => Ok(Expr::Literal(Value::Boolean(false))),
-        Token::Nil => Ok(Expr::Literal(Value::Nil)),
-        Token::Number(n) => Ok(Expr::Literal(Value::Number(*n))),
-        Token::String(s) => Ok(Expr::Literal(Value::String(s.clone()))),
-        Token::Identifier(i) => Ok(Expr::Variable(i.to_string())),
-        Token::LeftParen => parse_group(ps),
-        _ => Err(ParseError(format!(
-            "Failed matching primary {:?} {}",
-            token, token.line
-        ))),
+    if continues_interpolation(token) {
+        return Err(error_at(token, "Expect expression."));
     }
+
+    let kind = match &token.token_type {
+        Token::True => ExprKind::Literal(Value::Boolean(true)),
+        Token::False => ExprKind::Literal(Value::Boolean(false)),
+        Token::Nil => ExprKind::Literal(Value::Nil),
+        Token::Number(n) => ExprKind::Literal(Value::Number(*n)),
+        Token::String(s) => ExprKind::Literal(Value::String(intern_literal(s))),
+        Token::Identifier(i) => ExprKind::Variable(VarRef::new(Symbol::intern(i), token.span)),
+        Token::This => ExprKind::This(VarRef::new(Symbol::intern("this"), token.span)),
+        Token::Super => {
+            expect(ps, Token::Dot, "Expect '.' after 'super'.")?;
+            let method = expect_identifier(ps, "Expect superclass method name.")?;
+            ExprKind::Super(VarRef::new(Symbol::intern("super"), token.span), method.name)
+        }
+        Token::LeftParen => return parse_group(ps),
+        Token::Interpolation(_) => return parse_interpolation(ps),
+        _ => return Err(error_at(token, "Expect expression.")),
+    };
+    Ok(Expr::new(kind, span_from(ps, token.span)))
 }
 
 // Helpers
 
+// The most arguments a call, or parameters a function, can have
+const MAX_ARGUMENTS: usize = 255;
+
+// Build a binary or logical expression spanning both its operands
+fn binary(
+    kind: fn(Box<Expr>, Operator, Box<Expr>) -> ExprKind,
+    left: Expr,
+    operator: Operator,
+    right: Expr,
+) -> Expr {
+    let span = left.span.to(right.span);
+    Expr::new(kind(Box::new(left), operator, Box::new(right)), span)
+}
+
+// Skip tokens until just after a semicolon or the keyword starting a statement.
+// At least one token is skipped if nothing was consumed since `start`, so that
+// parsing always makes progress.
+fn synchronize(ps: &mut ParseState, start: usize) {
+    if ps.current == start {
+        advance(ps);
+    }
+    while !is_at_end(ps) {
+        if previous(ps).token_type == Token::Semicolon {
+            return;
+        }
+        match peek(ps).token_type {
+            Token::Class
+            | Token::Fun
+            | Token::Var
+            | Token::For
+            | Token::If
+            | Token::While
+            | Token::Print
+            | Token::Return => return,
+            _ => {
+                advance(ps);
+            }
+        }
+    }
+}
+
 // You are at the end if you encounter the Eof token or there are no more tokens
 fn is_at_end(ps: &ParseState) -> bool {
     match ps.source.get(ps.current) {
@@ -577,37 +967,72 @@ fn is_at_end(ps: &ParseState) -> bool {
     }
 }
 
-// advance moves the parser to the next token and returns the current one
-fn advance<'a>(ps: &'a mut ParseState) -> &'a TokenInstance {
+// advance moves the parser to the next token and returns the current one. The
+// Eof token is never moved past, so advancing at the end keeps returning it.
+fn advance<'a>(ps: &mut ParseState<'a>) -> &'a TokenInstance {
+    let token = peek(ps);
     if !is_at_end(ps) {
         ps.current += 1;
     }
-    previous(ps)
+    token
 }
 
 /// Expect will succeed and advance if the next token is the expected one, otherwise
 /// it will return an error (and not advance in case you want to recover)
-fn expect(ps: &mut ParseState, token: Token) -> Result<(), ParseError> {
-    let next = advance(ps).token_type.clone();
-    if next == token {
+fn expect(ps: &mut ParseState, token: Token, message: &str) -> Result<(), ParseError> {
+    let next = peek(ps);
+    if next.token_type == token {
+        advance(ps);
         Ok(())
     } else {
-        if ps.current > 0 {
-            ps.current -= 1;
+        Err(error_at(next, message))
+    }
+}
+
+/// Like expect but for an identifier, whose name is returned on success
+fn expect_identifier(ps: &mut ParseState, message: &str) -> Result<Identifier, ParseError> {
+    let next = peek(ps);
+    match &next.token_type {
+        Token::Identifier(name) => {
+            advance(ps);
+            Ok(Identifier {
+                name: Symbol::intern(name),
+                span: next.span,
+            })
         }
-        Err(ParseError(format!("Expected {} found {}", token, next)))
+        _ => Err(error_at(next, message)),
     }
 }
 
+// Build an error caused by `token`. Errors caused by the Eof token mean the
+// input stopped too soon rather than being wrong.
+fn error_at(token: &TokenInstance, message: &str) -> ParseError {
+    ParseError {
+        message: message.to_string(),
+        span: token.span,
+        incomplete: token.token_type == Token::Eof,
+    }
+}
+
+// Record an error that the parser can carry on from without synchronizing
+fn report(ps: &mut ParseState, err: ParseError) {
+    ps.errors.push(err);
+}
+
+// The span from `start` up to the end of the last token consumed
+fn span_from(ps: &ParseState, start: Span) -> Span {
+    start.to(previous(ps).span)
+}
+
 // TODO could remove these unwraps and return Result
-// previous returns the token prior to the one at the current parsing position
-fn previous<'a>(ps: &'a ParseState) -> &'a TokenInstance {
-    ps.source.get(ps.current - 1).unwrap()
+// previous returns the last token consumed, or the first token if there is none
+fn previous<'a>(ps: &ParseState<'a>) -> &'a TokenInstance {
+    ps.source.get(ps.current.saturating_sub(1)).unwrap()
 }
 
 // As you'd expect peek returns the token at the current parsing position
 // without advancing
-fn peek<'a>(ps: &'a ParseState) -> &'a TokenInstance {
+fn peek<'a>(ps: &ParseState<'a>) -> &'a TokenInstance {
     ps.source.get(ps.current).unwrap()
 }
 
@@ -617,12 +1042,94 @@ mod tests {
 
     #[test]
     fn test_display_expression_kitchen_sink() {
-        let expr: Expr = Expr::Binary(
-            Box::new(Expr::Literal(Value::Number(100.0))),
+        let expr: Expr = binary(
+            ExprKind::Binary,
+            Expr::new(ExprKind::Literal(Value::Number(100.0)), Span::default()),
             Operator::Plus,
-            Box::new(Expr::Literal(Value::Number(200.0))),
+            Expr::new(ExprKind::Literal(Value::Number(200.0)), Span::default()),
         );
 
         assert_eq!("(+ 100.0 200.0)", format!("{}", expr));
     }
+
+    #[test]
+    fn test_parse_function_declaration() {
+        let tokens = crate::scan::scan("fun add(a, b) { return a + b; }").unwrap();
+        let stmts = parse(&tokens).unwrap();
+
+        assert_eq!(1, stmts.len());
+        assert_eq!("fun add(a, b) {return (+ a b);}", format!("{}", stmts[0]));
+    }
+
+    #[test]
+    fn test_parse_incomplete_input() {
+        let parse_source = |source: &str| parse(&crate::scan::scan(source).unwrap());
+
+        assert!(parse_source("{ print 1;").unwrap_err()[0].is_incomplete());
+        assert!(parse_source("print (1 +").unwrap_err()[0].is_incomplete());
+        assert!(parse_source("fun f(a,").unwrap_err()[0].is_incomplete());
+        assert!(!parse_source("print (1 + );").unwrap_err()[0].is_incomplete());
+        // Every statement needs its semicolon, the REPL only lets a lone
+        // expression go without
+        let errors = parse_source("print 1").unwrap_err();
+        assert_eq!("Expect ';' after value.", errors[0].message);
+        assert!(parse_source("1 + 2").is_err());
+        let stmts = parse_line(&crate::scan::scan("1 + 2").unwrap()).unwrap();
+        assert!(matches!(stmts[..], [Stmt { kind: StmtKind::Expression(_), .. }]));
+        assert!(parse_line(&crate::scan::scan("print 1").unwrap()).unwrap_err()[0].is_incomplete());
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        let parse_source = |source: &str| parse(&crate::scan::scan(source).unwrap());
+
+        let stmts = parse_source("print 1;\nvar a = 1 +\n  foo(2);").unwrap();
+        assert_eq!(Span::new(0, 8, 1, 1), stmts[0].span);
+        assert_eq!(Span::new(9, 21, 2, 1), stmts[1].span);
+        let StmtKind::VarDecl(_, Some(initializer)) = &stmts[1].kind else {
+            panic!("expected var declaration")
+        };
+        assert_eq!(Span::new(17, 12, 2, 9), initializer.span);
+
+        let errors = parse_source("print 1;\nprint (1 + );").unwrap_err();
+        assert_eq!(Span::new(20, 1, 2, 12), errors[0].span());
+    }
+
+    #[test]
+    fn test_parse_error_recovery() {
+        let parse_source = |source: &str| parse(&crate::scan::scan(source).unwrap());
+        let lines = |source: &str| -> Vec<usize> {
+            parse_source(source)
+                .unwrap_err()
+                .iter()
+                .map(|err| err.span().line)
+                .collect()
+        };
+
+        // One error for each bad statement, including those inside blocks and
+        // function bodies
+        assert_eq!(vec![1, 3], lines("var a = ;\nprint 1;\nprint 1 2;\nprint 3;"));
+        assert_eq!(vec![2, 4], lines("{\n  var = 1;\n  print 2;\n  print );\n}"));
+        assert_eq!(vec![2, 3], lines("fun f() {\n  return +;\n  1 = 2;\n}\nf();"));
+        // Missing braces at the end are reported once
+        assert_eq!(vec![1], lines("{ { print 1;"));
+        // Recovery can't loop forever on a token that starts nothing
+        assert_eq!(vec![1], lines(")"));
+    }
+
+    #[test]
+    fn test_parse_interpolation() {
+        let parse_source = |source: &str| parse(&crate::scan::scan(source).unwrap());
+
+        let stmts = parse_source("print \"a ${b} ${\"c${d}\"}\";").unwrap();
+        assert_eq!("print (interpolate a  b   (interpolate c d));", format!("{}", stmts[0]));
+        let StmtKind::Print(expr) = &stmts[0].kind else {
+            panic!("expected print")
+        };
+        assert_eq!(Span::new(6, 19, 1, 7), expr.span);
+
+        let message = |source: &str| parse_source(source).unwrap_err()[0].message.clone();
+        assert_eq!("Expect expression.", message("print \"${}\";"));
+        assert_eq!("Expect '}' after expression.", message("print \"${a b}\";"));
+    }
 }
@@ -0,0 +1,350 @@
+// Resolver for Lox
+// A static pass over the parsed statements, run before evaluation, that works
+// out which scope each variable use refers to and reports errors that can be
+// found without running the program.
+use crate::intern::Symbol;
+use crate::parse::{Expr, ExprKind, FunctionDecl, Identifier, Stmt, StmtKind, VarRef};
+use crate::scan::Span;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct ResolveError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ResolveError {
+    fn new(message: impl Into<String>, span: Span) -> Self {
+        ResolveError {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// span is the location of the name or keyword that caused the error
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+// Each scope maps a variable name to whether its initializer has finished
+// resolving. The global scope is not tracked, anything not found in a local
+// scope is assumed to be a global. Errors don't stop the resolver, they are
+// collected so that all of them can be reported.
+struct ResolveState {
+    scopes: Vec<HashMap<Symbol, bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+    errors: Vec<ResolveError>,
+}
+
+/// Resolve every variable use in `stmts`, recording its scope depth in the
+/// syntax tree. Scopes must mirror the ones created by eval: one for each
+/// block, each branch of an if, each while body and each function call, plus
+/// the scope holding `this` that is created when a method is bound.
+pub fn resolve(stmts: &[Stmt]) -> Result<(), Vec<ResolveError>> {
+    let mut rs = ResolveState {
+        scopes: vec![],
+        current_function: FunctionType::None,
+        current_class: ClassType::None,
+        errors: vec![],
+    };
+    resolve_statements(&mut rs, stmts);
+    if rs.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(rs.errors)
+    }
+}
+
+fn error(rs: &mut ResolveState, message: &str, span: Span) {
+    rs.errors.push(ResolveError::new(message, span));
+}
+
+fn resolve_statements(rs: &mut ResolveState, stmts: &[Stmt]) {
+    for stmt in stmts {
+        resolve_statement(rs, stmt);
+    }
+}
+
+fn resolve_block(rs: &mut ResolveState, stmts: &[Stmt]) {
+    rs.scopes.push(HashMap::new());
+    resolve_statements(rs, stmts);
+    rs.scopes.pop();
+}
+
+fn resolve_statement(rs: &mut ResolveState, stmt: &Stmt) {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => resolve_block(rs, stmts),
+        StmtKind::VarDecl(name, initializer) => {
+            declare(rs, name);
+            if let Some(expr) = initializer {
+                resolve_expression(rs, expr);
+            }
+            define(rs, name);
+        }
+        StmtKind::Function(declaration) => {
+            declare(rs, &declaration.name);
+            define(rs, &declaration.name);
+            resolve_function(rs, declaration, FunctionType::Function);
+        }
+        StmtKind::Class(name, superclass, methods) => {
+            declare(rs, name);
+            define(rs, name);
+            resolve_class(rs, name, superclass.as_ref(), methods);
+        }
+        StmtKind::Expression(expr) | StmtKind::Print(expr) => resolve_expression(rs, expr),
+        StmtKind::If(cond, then_stmts, else_stmts) => {
+            resolve_expression(rs, cond);
+            resolve_block(rs, then_stmts);
+            resolve_block(rs, else_stmts);
+        }
+        StmtKind::While(cond, stmts) => {
+            resolve_expression(rs, cond);
+            resolve_block(rs, stmts);
+        }
+        StmtKind::Return(expr) => {
+            // Errors point at the return keyword at the start of the statement
+            let keyword = Span {
+                len: "return".len(),
+                ..stmt.span
+            };
+            if rs.current_function == FunctionType::None {
+                error(rs, "Can't return from top-level code.", keyword);
+            }
+            if let Some(expr) = expr {
+                if rs.current_function == FunctionType::Initializer {
+                    error(rs, "Can't return a value from an initializer.", keyword);
+                }
+                resolve_expression(rs, expr);
+            }
+        }
+    }
+}
+
+// A subclass gets an extra scope holding `super` around the one holding `this`,
+// mirroring the scopes eval creates for its methods
+fn resolve_class(
+    rs: &mut ResolveState,
+    name: &Identifier,
+    superclass: Option<&VarRef>,
+    methods: &[Rc<FunctionDecl>],
+) {
+    let enclosing_class = rs.current_class;
+    rs.current_class = ClassType::Class;
+
+    if let Some(superclass) = superclass {
+        if superclass.name == name.name {
+            error(rs, "A class can't inherit from itself.", superclass.span);
+        }
+        rs.current_class = ClassType::Subclass;
+        resolve_local(rs, superclass);
+        rs.scopes.push(HashMap::from([(Symbol::intern("super"), true)]));
+    }
+    rs.scopes.push(HashMap::from([(Symbol::intern("this"), true)]));
+
+    for method in methods {
+        let function_type = if method.name.name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        resolve_function(rs, method, function_type);
+    }
+
+    rs.scopes.pop();
+    if superclass.is_some() {
+        rs.scopes.pop();
+    }
+    rs.current_class = enclosing_class;
+}
+
+// The parameters and the body share a single scope, just as they share the
+// symbol table created by the call
+fn resolve_function(rs: &mut ResolveState, declaration: &FunctionDecl, function_type: FunctionType) {
+    let enclosing_function = rs.current_function;
+    rs.current_function = function_type;
+    rs.scopes.push(HashMap::new());
+
+    for param in &declaration.params {
+        declare(rs, param);
+        define(rs, param);
+    }
+    resolve_statements(rs, &declaration.body);
+
+    rs.scopes.pop();
+    rs.current_function = enclosing_function;
+}
+
+fn resolve_expression(rs: &mut ResolveState, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Variable(var) => {
+            if let Some(scope) = rs.scopes.last()
+                && scope.get(&var.name) == Some(&false)
+            {
+                error(rs, "Can't read local variable in its own initializer.", var.span);
+            }
+            resolve_local(rs, var);
+        }
+        ExprKind::Assign(var, value) => {
+            resolve_expression(rs, value);
+            resolve_local(rs, var);
+        }
+        ExprKind::Binary(left, _, right) | ExprKind::Logical(left, _, right) => {
+            resolve_expression(rs, left);
+            resolve_expression(rs, right);
+        }
+        ExprKind::Call(callee, arguments) => {
+            resolve_expression(rs, callee);
+            for argument in arguments {
+                resolve_expression(rs, argument);
+            }
+        }
+        ExprKind::Get(object, _) => resolve_expression(rs, object),
+        ExprKind::Set(object, _, value) => {
+            resolve_expression(rs, value);
+            resolve_expression(rs, object);
+        }
+        ExprKind::Super(var, _) => {
+            match rs.current_class {
+                ClassType::None => error(rs, "Can't use 'super' outside of a class.", var.span),
+                ClassType::Class => error(
+                    rs,
+                    "Can't use 'super' in a class with no superclass.",
+                    var.span,
+                ),
+                ClassType::Subclass => {}
+            }
+            resolve_local(rs, var);
+        }
+        ExprKind::This(var) => {
+            if rs.current_class == ClassType::None {
+                error(rs, "Can't use 'this' outside of a class.", var.span);
+            }
+            resolve_local(rs, var);
+        }
+        ExprKind::Grouping(expr) | ExprKind::Unary(_, expr) => resolve_expression(rs, expr),
+        ExprKind::Interpolation(parts) => {
+            for part in parts {
+                resolve_expression(rs, part);
+            }
+        }
+        ExprKind::Literal(_) => {}
+    }
+}
+
+// Find the innermost scope declaring the variable and record how far away it is
+fn resolve_local(rs: &ResolveState, var: &VarRef) {
+    let found = rs
+        .scopes
+        .iter()
+        .rev()
+        .position(|scope| scope.contains_key(&var.name));
+    var.depth.set(found);
+}
+
+fn declare(rs: &mut ResolveState, name: &Identifier) {
+    let Some(scope) = rs.scopes.last_mut() else {
+        return;
+    };
+    if scope.insert(name.name, false).is_some() {
+        error(rs, "Already a variable with this name in this scope.", name.span);
+    }
+}
+
+fn define(rs: &mut ResolveState, name: &Identifier) {
+    if let Some(scope) = rs.scopes.last_mut() {
+        scope.insert(name.name, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+    use crate::scan::scan;
+
+    fn resolve_source(source: &str) -> Result<Vec<Stmt>, Vec<ResolveError>> {
+        let stmts = parse(&scan(source).unwrap()).unwrap();
+        resolve(&stmts)?;
+        Ok(stmts)
+    }
+
+    #[test]
+    fn test_resolve_depths() {
+        let stmts = resolve_source("var g; { var a; { print a; print g; } }").unwrap();
+
+        let StmtKind::Block(outer) = &stmts[1].kind else {
+            panic!("expected block")
+        };
+        let StmtKind::Block(inner) = &outer[1].kind else {
+            panic!("expected block")
+        };
+        let depth_of = |stmt: &Stmt| match &stmt.kind {
+            StmtKind::Print(Expr {
+                kind: ExprKind::Variable(var),
+                ..
+            }) => var.depth.get(),
+            _ => panic!("expected print of a variable"),
+        };
+        assert_eq!(Some(1), depth_of(&inner[0]));
+        assert_eq!(None, depth_of(&inner[1]));
+    }
+
+    #[test]
+    fn test_resolve_static_errors() {
+        assert!(resolve_source("{ var a = a; }").is_err());
+        assert!(resolve_source("{ var a; var a; }").is_err());
+        assert!(resolve_source("fun f(a, a) {}").is_err());
+        assert!(resolve_source("return 1;").is_err());
+        assert!(resolve_source("print this;").is_err());
+        assert!(resolve_source("class A { init() { return 1; } }").is_err());
+        assert!(resolve_source("class A { init() { return; } }").is_ok());
+        assert!(resolve_source("class A < A {}").is_err());
+        assert!(resolve_source("class A { f() { super.f(); } }").is_err());
+        assert!(resolve_source("fun f() { super.f(); }").is_err());
+        assert!(resolve_source("class A {} class B < A { f() { super.f(); } }").is_ok());
+        assert!(resolve_source("var a = 1; var a = a;").is_ok());
+    }
+
+    #[test]
+    fn test_resolve_reports_all_errors() {
+        let errors = resolve_source("super.foo();\n{ var a = 1; var a = a; }\nreturn 1;").unwrap_err();
+        let messages: Vec<(usize, &str)> = errors
+            .iter()
+            .map(|err| (err.span().line, err.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, "Can't use 'super' outside of a class."),
+                (2, "Already a variable with this name in this scope."),
+                (2, "Can't read local variable in its own initializer."),
+                (3, "Can't return from top-level code."),
+            ],
+            messages
+        );
+    }
+}
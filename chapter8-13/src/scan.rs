@@ -1,8 +1,17 @@
 // Scanner for Lox
-// Tools to turn a string of lox source into tokens
+// Tools to turn a string of lox source into tokens. Source is UTF-8: strings
+// and comments may hold any text, and identifiers are made of Unicode letters
+// and digits, as defined by XID_Start and XID_Continue, as well as `_`.
+// Strings may use the escapes \" \\ \n \t \r \0 \$ and \u{XXXX}, except in raw
+// strings, written r"...", which are taken exactly as they appear. A string
+// holding `${expression}` is split into segments of text, each one that is
+// followed by an expression being an Interpolation token, with the tokens of
+// the expression between them. Comments run from // to the end of the line,
+// or from /* to */, and those can be nested.
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::fmt;
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 #[derive(PartialEq, Clone)]
 pub enum Token {
@@ -10,6 +19,7 @@ pub enum Token {
     Identifier(String),
     Number(f64),
     String(String),
+    Interpolation(String),
     // single character operators
     Equal,
     LeftParen,
@@ -53,11 +63,43 @@ pub enum Token {
     Eof,
 }
 
+/// Where some text is in the source. `offset` and `len` are in bytes, while `line`
+/// and `column` count from 1 and give the position of the first character, with
+/// the column counted in characters rather than bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(offset: usize, len: usize, line: usize, column: usize) -> Self {
+        Span {
+            offset,
+            len,
+            line,
+            column,
+        }
+    }
+    /// to makes a span from the start of this one to the end of `end`
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            len: (end.offset + end.len).saturating_sub(self.offset),
+            ..self
+        }
+    }
+}
+
+/// A token as it was found in the source. `lexeme` is the text of the token
+/// exactly as written, so for a string it includes the quotes and any escapes,
+/// which the value in `token_type` has decoded.
 #[derive(PartialEq)]
 pub struct TokenInstance {
     pub token_type: Token,
     pub lexeme: String,
-    pub line: usize,
+    pub span: Span,
 }
 
 // We want to emulate the String format of Double in Java to make the tests pass
@@ -85,7 +127,8 @@ impl fmt::Debug for TokenInstance {
         match &self.token_type {
             Token::Identifier(string) => write!(f, "IDENTIFIER {} null", string),
             Token::Number(num) => write!(f, "NUMBER {} {}", self.lexeme, num_format(*num)),
-            Token::String(string) => write!(f, "STRING \"{}\" {}", self.lexeme, string),
+            Token::String(string) => write!(f, "STRING {} {}", self.lexeme, string),
+            Token::Interpolation(string) => write!(f, "INTERPOLATION {} {}", self.lexeme, string),
             Token::Equal => write!(f, "EQUAL = null"),
             Token::LeftParen => write!(f, "LEFT_PAREN ( null"),
             Token::RightParen => write!(f, "RIGHT_PAREN ) null"),
@@ -132,6 +175,7 @@ impl fmt::Display for Token {
             Token::Identifier(string) => write!(f, "{}", string),
             Token::Number(num) => write!(f, "{}", num_format(*num)),
             Token::String(string) => write!(f, "\"{}\"", string),
+            Token::Interpolation(string) => write!(f, "\"{}${{", string),
             Token::Equal => write!(f, "="),
             Token::LeftParen => write!(f, "("),
             Token::RightParen => write!(f, ")"),
@@ -174,12 +218,46 @@ impl fmt::Display for Token {
 
 #[derive(Debug)]
 pub enum ScanError {
-    UnexpectedChar(char),
-    NumberFormatError(String),
-    UnterminatedString(String),
-    EndOfInput,
+    UnexpectedChar(char, Span),
+    NumberFormatError(String, Span),
+    UnterminatedString(Span),
+    InvalidEscape(String, Span),
+    UnterminatedComment(Span),
+}
+
+impl ScanError {
+    /// span is the location of the offending source text
+    pub fn span(&self) -> Span {
+        match self {
+            ScanError::UnexpectedChar(_, span)
+            | ScanError::NumberFormatError(_, span)
+            | ScanError::InvalidEscape(_, span)
+            | ScanError::UnterminatedString(span)
+            | ScanError::UnterminatedComment(span) => *span,
+        }
+    }
 }
 
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::UnexpectedChar(_, _) => write!(f, "Unexpected character."),
+            ScanError::NumberFormatError(number, _) => write!(f, "Invalid number {}", number),
+            ScanError::UnterminatedString(_) => write!(f, "Unterminated string."),
+            ScanError::InvalidEscape(escape, _) => write!(f, "Invalid escape sequence '{}'.", escape),
+            ScanError::UnterminatedComment(_) => write!(f, "Unterminated comment."),
+        }
+    }
+}
+
+// `start` and `current` delimit the token being scanned. They are byte offsets
+// into the source, always on a character boundary, so that looking at the next
+// character takes the same time wherever it is. `column` is the column of
+// `current`, counted in characters, and the line and column the token started
+// at are kept for tokens, such as strings, that may run over several lines.
+// `interpolations` has an entry for each interpolated expression being
+// scanned, with the span of the segment before it and how many braces are open
+// in it, so that the `}` ending it can be told apart from one ending a block.
 #[derive(Debug)]
 struct ScanState<'a> {
     line: usize,
@@ -187,51 +265,79 @@ struct ScanState<'a> {
     source: &'a str,
     start: usize,
     current: usize,
+    column: usize,
+    start_line: usize,
+    start_column: usize,
+    interpolations: Vec<(Span, usize)>,
 }
 
-fn begin_scan(source: &str) -> ScanState {
+fn begin_scan(source: &str) -> ScanState<'_> {
     ScanState {
         line: 1,
         tokens: vec![],
         source,
         start: 0,
         current: 0,
+        column: 1,
+        start_line: 1,
+        start_column: 1,
+        interpolations: vec![],
     }
 }
 
+// The span of the token being scanned
+fn token_span(state: &ScanState) -> Span {
+    Span::new(
+        state.start,
+        state.current - state.start,
+        state.start_line,
+        state.start_column,
+    )
+}
+
+// Mark the start of the next token at the current position
+fn begin_token(state: &mut ScanState) {
+    state.start = state.current;
+    state.start_line = state.line;
+    state.start_column = state.column;
+}
+
+// Called after consuming a new line character
+fn new_line(state: &mut ScanState) {
+    state.line += 1;
+    state.column = 1;
+}
+
 fn is_scan_done(state: &ScanState) -> bool {
-    state.current == state.source.len()
+    state.current >= state.source.len()
+}
+
+// The source that is still to be scanned
+fn rest<'a>(state: &ScanState<'a>) -> &'a str {
+    &state.source[state.current..]
 }
 
 fn peek(state: &ScanState) -> char {
-    if is_scan_done(state) {
-        '\0'
-    } else {
-        state.source.chars().nth(state.current).unwrap()
-    }
+    rest(state).chars().next().unwrap_or('\0')
 }
 
 fn peek_next(state: &ScanState) -> char {
-    if state.current + 1 >= state.source.len() {
-        '\0'
-    } else {
-        state.source.chars().nth(state.current + 1).unwrap()
-    }
+    rest(state).chars().nth(1).unwrap_or('\0')
 }
 
 fn advance(state: &mut ScanState) -> char {
-    let c = state.source.chars().nth(state.current);
-    state.current += 1;
-    c.unwrap()
+    let c = peek(state);
+    state.current += c.len_utf8();
+    state.column += 1;
+    c
 }
 
 fn match_next(n: char, state: &mut ScanState) -> bool {
-    match state.source.chars().nth(state.current) {
-        Some(d) if n == d => {
-            state.current += 1;
-            true
-        }
-        _ => false,
+    if !is_scan_done(state) && peek(state) == n {
+        advance(state);
+        true
+    } else {
+        false
     }
 }
 
@@ -244,8 +350,24 @@ fn scan_next(state: &mut ScanState) -> Result<(), ScanError> {
         // Single characters
         '(' => single_character_scanner(next_char, Token::LeftParen, state),
         ')' => single_character_scanner(next_char, Token::RightParen, state),
-        '{' => single_character_scanner(next_char, Token::LeftBrace, state),
-        '}' => single_character_scanner(next_char, Token::RightBrace, state),
+        '{' => {
+            if let Some((_, braces)) = state.interpolations.last_mut() {
+                *braces += 1;
+            }
+            single_character_scanner(next_char, Token::LeftBrace, state)
+        }
+        '}' => match state.interpolations.last_mut() {
+            // The end of an interpolated expression, the string carries on
+            Some((_, 0)) => {
+                state.interpolations.pop();
+                string_scanner(state, true)
+            }
+            Some((_, braces)) => {
+                *braces -= 1;
+                single_character_scanner(next_char, Token::RightBrace, state)
+            }
+            None => single_character_scanner(next_char, Token::RightBrace, state),
+        },
         ',' => single_character_scanner(next_char, Token::Comma, state),
         '.' => single_character_scanner(next_char, Token::Dot, state),
         '-' => single_character_scanner(next_char, Token::Minus, state),
@@ -278,11 +400,16 @@ fn scan_next(state: &mut ScanState) -> Result<(), ScanError> {
         '/' => slash_or_comment_scanner(state),
         // Numbers
         m if m.is_ascii_digit() => number_scanner(state),
+        // Raw string literals
+        'r' if peek(state) == '"' => {
+            advance(state);
+            string_scanner(state, false)
+        }
         // Identifiers
-        m if m.is_ascii_alphabetic() || m == '_' => identifier_or_keyword_scanner(state),
+        m if is_identifier_start(m) => identifier_or_keyword_scanner(state),
         // String literals
-        '"' => string_scanner(state),
-        _ => Err(ScanError::UnexpectedChar(next_char)),
+        '"' => string_scanner(state, true),
+        _ => Err(ScanError::UnexpectedChar(next_char, token_span(state))),
     }
 }
 
@@ -309,52 +436,122 @@ lazy_static! {
     };
 }
 
-fn string_scanner(state: &mut ScanState) -> Result<(), ScanError> {
+// Scan a string, or the segment of one following an interpolated expression,
+// whose opening quote or brace has been consumed. Escapes and interpolation
+// are only done when `escapes` is set. An invalid escape is reported once the
+// whole segment has been consumed, so that scanning carries on after it.
+fn string_scanner(state: &mut ScanState, escapes: bool) -> Result<(), ScanError> {
+    let mut value = String::new();
+    let mut escape_error = None;
     while peek(state) != '"' && !is_scan_done(state) {
-        if peek(state) == '\n' {
-            state.line += 1;
+        match advance(state) {
+            '$' if escapes && peek(state) == '{' => {
+                advance(state);
+                state.interpolations.push((token_span(state), 0));
+                state.tokens.push(TokenInstance {
+                    token_type: Token::Interpolation(value),
+                    lexeme: state.source[state.start..state.current].to_string(),
+                    span: token_span(state),
+                });
+                return escape_error.map_or(Ok(()), Err);
+            }
+            '\n' => {
+                new_line(state);
+                value.push('\n');
+            }
+            // The string is still a token after a bad escape, so that the
+            // parser carries on without reporting errors of its own
+            '\\' if escapes && !is_scan_done(state) => match escape_scanner(state) {
+                Ok(c) => value.push(c),
+                Err(err) => {
+                    value.push(char::REPLACEMENT_CHARACTER);
+                    escape_error.get_or_insert(err);
+                }
+            },
+            c => value.push(c),
         }
-        advance(state);
     }
 
     if is_scan_done(state) {
-        Err(ScanError::UnterminatedString(format!(
-            "Unterminated string {:?}",
-            state.source
-        )))
+        Err(ScanError::UnterminatedString(token_span(state)))
     } else {
         advance(state);
-        let word = &state.source[state.start + 1..state.current - 1];
         state.tokens.push(TokenInstance {
-            token_type: Token::String(word.to_string()),
-            lexeme: word.to_string(),
-            line: state.line,
+            token_type: Token::String(value),
+            lexeme: state.source[state.start..state.current].to_string(),
+            span: token_span(state),
         });
-        Ok(())
+        escape_error.map_or(Ok(()), Err)
     }
 }
 
-fn identifier_or_keyword_scanner(state: &mut ScanState) -> Result<(), ScanError> {
-    loop {
-        let peeked = peek(state);
-        if peeked.is_ascii_alphanumeric() || peeked == '_' {
-            advance(state);
-        } else {
-            break;
+// Decode the escape sequence following a backslash
+fn escape_scanner(state: &mut ScanState) -> Result<char, ScanError> {
+    let span = Span::new(state.current - 1, 0, state.line, state.column - 1);
+    let decoded = match advance(state) {
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '$' => Some('$'),
+        'u' => unicode_escape_scanner(state),
+        '\n' => {
+            new_line(state);
+            None
         }
+        _ => None,
+    };
+    decoded.ok_or_else(|| {
+        let escape = &state.source[span.offset..state.current];
+        ScanError::InvalidEscape(
+            escape.to_string(),
+            Span {
+                len: escape.len(),
+                ..span
+            },
+        )
+    })
+}
+
+// Decode the `{XXXX}` of a `\u{XXXX}` escape, which is from one to six hex
+// digits giving a Unicode scalar value
+fn unicode_escape_scanner(state: &mut ScanState) -> Option<char> {
+    if !match_next('{', state) {
+        return None;
+    }
+    let digits_start = state.current;
+    while peek(state).is_ascii_hexdigit() {
+        advance(state);
+    }
+    let digits = &state.source[digits_start..state.current];
+    if !match_next('}', state) || digits.is_empty() || digits.len() > 6 {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok().and_then(char::from_u32)
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || is_xid_start(c)
+}
+
+fn identifier_or_keyword_scanner(state: &mut ScanState) -> Result<(), ScanError> {
+    while !is_scan_done(state) && is_xid_continue(peek(state)) {
+        advance(state);
     }
     let word = &state.source[state.start..state.current];
     if let Some(keyword_token) = KEY_WORDS.get(word) {
         state.tokens.push(TokenInstance {
             token_type: keyword_token.clone(),
             lexeme: word.to_string(),
-            line: state.line,
+            span: token_span(state),
         })
     } else {
         state.tokens.push(TokenInstance {
             token_type: Token::Identifier(word.to_string()),
             lexeme: word.to_string(),
-            line: state.line,
+            span: token_span(state),
         })
     }
     Ok(())
@@ -378,11 +575,14 @@ fn number_scanner(state: &mut ScanState) -> Result<(), ScanError> {
             state.tokens.push(TokenInstance {
                 token_type: Token::Number(value),
                 lexeme: number_str.to_string(),
-                line: state.line,
+                span: token_span(state),
             });
             Ok(())
         }
-        Err(_) => Err(ScanError::NumberFormatError(number_str.to_string())),
+        Err(_) => Err(ScanError::NumberFormatError(
+            number_str.to_string(),
+            token_span(state),
+        )),
     }
 }
 
@@ -391,22 +591,47 @@ fn slash_or_comment_scanner(state: &mut ScanState) -> Result<(), ScanError> {
         while peek(state) != '\n' && !is_scan_done(state) {
             advance(state);
         }
+    } else if match_next('*', state) {
+        return block_comment_scanner(state);
     } else {
         state.tokens.push(TokenInstance {
             token_type: Token::Slash,
             lexeme: '/'.to_string(),
-            line: state.line,
+            span: token_span(state),
         })
     }
     Ok(())
 }
 
+// Skip a block comment whose opening /* has been consumed. Each /* inside it
+// opens a nested comment, so it ends at the */ matching the first one.
+fn block_comment_scanner(state: &mut ScanState) -> Result<(), ScanError> {
+    let mut depth = 1;
+    while depth > 0 {
+        if is_scan_done(state) {
+            // Point at where the comment was opened
+            let span = Span {
+                len: 2,
+                ..token_span(state)
+            };
+            return Err(ScanError::UnterminatedComment(span));
+        }
+        match advance(state) {
+            '\n' => new_line(state),
+            '/' if match_next('*', state) => depth += 1,
+            '*' if match_next('/', state) => depth -= 1,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 // Handle single-character
 fn single_character_scanner(c: char, token: Token, state: &mut ScanState) -> Result<(), ScanError> {
     state.tokens.push(TokenInstance {
         token_type: token,
         lexeme: c.to_string(),
-        line: state.line,
+        span: token_span(state),
     });
     Ok(())
 }
@@ -423,35 +648,54 @@ fn single_or_double_character_scanner(
         state.tokens.push(TokenInstance {
             token_type: double_token,
             lexeme: String::from_iter(c_arr),
-            line: state.line,
+            span: token_span(state),
         })
     } else {
         state.tokens.push(TokenInstance {
             token_type: single_token,
             lexeme: c.to_string(),
-            line: state.line,
+            span: token_span(state),
         })
     }
     Ok(())
 }
 
 fn skip_character_new_line(state: &mut ScanState) -> Result<(), ScanError> {
-    state.line += 1;
+    new_line(state);
     Ok(())
 }
 
-pub fn scan(input: &str) -> Result<Vec<TokenInstance>, ScanError> {
+/// Scan `input` into tokens, failing with every lexical error found
+pub fn scan(input: &str) -> Result<Vec<TokenInstance>, Vec<ScanError>> {
+    match scan_with_errors(input) {
+        (tokens, errors) if errors.is_empty() => Ok(tokens),
+        (_, errors) => Err(errors),
+    }
+}
+
+/// Scan `input` into tokens, carrying on past errors. The text that caused each
+/// error is skipped, so the tokens are still worth parsing to find more errors.
+pub fn scan_with_errors(input: &str) -> (Vec<TokenInstance>, Vec<ScanError>) {
     let mut state: ScanState = begin_scan(input);
+    let mut errors = vec![];
     while !is_scan_done(&state) {
-        state.start = state.current;
-        scan_next(&mut state)?;
+        begin_token(&mut state);
+        // Every error is raised after consuming the offending text, so scanning
+        // can simply continue from where it is
+        if let Err(err) = scan_next(&mut state) {
+            errors.push(err);
+        }
     }
+    for (span, _) in state.interpolations.drain(..).rev() {
+        errors.push(ScanError::UnterminatedString(span));
+    }
+    begin_token(&mut state);
     state.tokens.push(TokenInstance {
         token_type: Token::Eof,
         lexeme: "".to_string(),
-        line: state.line,
+        span: token_span(&state),
     });
-    Ok(state.tokens)
+    (state.tokens, errors)
 }
 
 #[cfg(test)]
@@ -467,17 +711,17 @@ mod tests {
             TokenInstance {
                 token_type: Token::Equal,
                 lexeme: "=".to_string(),
-                line: 1,
+                span: Span::new(0, 1, 1, 1),
             },
             TokenInstance {
                 token_type: Token::Plus,
                 lexeme: "+".to_string(),
-                line: 1,
+                span: Span::new(1, 1, 1, 2),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 1,
+                span: Span::new(2, 0, 1, 3),
             },
         ];
 
@@ -492,37 +736,37 @@ mod tests {
             TokenInstance {
                 token_type: Token::Identifier("a".to_string()),
                 lexeme: "a".to_string(),
-                line: 1,
+                span: Span::new(1, 1, 1, 2),
             },
             TokenInstance {
                 token_type: Token::Equal,
                 lexeme: "=".to_string(),
-                line: 1,
+                span: Span::new(3, 1, 1, 4),
             },
             TokenInstance {
                 token_type: Token::Number(1.0),
                 lexeme: "1".to_string(),
-                line: 1,
+                span: Span::new(5, 1, 1, 6),
             },
             TokenInstance {
                 token_type: Token::Plus,
                 lexeme: "+".to_string(),
-                line: 1,
+                span: Span::new(7, 1, 1, 8),
             },
             TokenInstance {
                 token_type: Token::Number(2.0),
                 lexeme: "2".to_string(),
-                line: 1,
+                span: Span::new(9, 1, 1, 10),
             },
             TokenInstance {
                 token_type: Token::Semicolon,
                 lexeme: ";".to_string(),
-                line: 1,
+                span: Span::new(11, 1, 1, 12),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 1,
+                span: Span::new(13, 0, 1, 14),
             },
         ];
 
@@ -537,37 +781,37 @@ mod tests {
             TokenInstance {
                 token_type: Token::Identifier("a".to_string()),
                 lexeme: "a".to_string(),
-                line: 1,
+                span: Span::new(0, 1, 1, 1),
             },
             TokenInstance {
                 token_type: Token::Equal,
                 lexeme: "=".to_string(),
-                line: 1,
+                span: Span::new(1, 1, 1, 2),
             },
             TokenInstance {
                 token_type: Token::Number(1.0),
                 lexeme: "1".to_string(),
-                line: 1,
+                span: Span::new(2, 1, 1, 3),
             },
             TokenInstance {
                 token_type: Token::Plus,
                 lexeme: "+".to_string(),
-                line: 1,
+                span: Span::new(3, 1, 1, 4),
             },
             TokenInstance {
                 token_type: Token::Number(2.0),
                 lexeme: "2".to_string(),
-                line: 1,
+                span: Span::new(4, 1, 1, 5),
             },
             TokenInstance {
                 token_type: Token::Semicolon,
                 lexeme: ";".to_string(),
-                line: 1,
+                span: Span::new(5, 1, 1, 6),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 1,
+                span: Span::new(6, 0, 1, 7),
             },
         ];
 
@@ -582,32 +826,32 @@ mod tests {
             TokenInstance {
                 token_type: Token::Identifier("a".to_string()),
                 lexeme: "a".to_string(),
-                line: 1,
+                span: Span::new(0, 1, 1, 1),
             },
             TokenInstance {
                 token_type: Token::Equal,
                 lexeme: "=".to_string(),
-                line: 1,
+                span: Span::new(1, 1, 1, 2),
             },
             TokenInstance {
                 token_type: Token::Identifier("b".to_string()),
                 lexeme: "b".to_string(),
-                line: 2,
+                span: Span::new(4, 1, 2, 1),
             },
             TokenInstance {
                 token_type: Token::Plus,
                 lexeme: "+".to_string(),
-                line: 2,
+                span: Span::new(5, 1, 2, 2),
             },
             TokenInstance {
                 token_type: Token::Identifier("c".to_string()),
                 lexeme: "c".to_string(),
-                line: 2,
+                span: Span::new(6, 1, 2, 3),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 2,
+                span: Span::new(7, 0, 2, 4),
             },
         ];
 
@@ -625,81 +869,81 @@ mod tests {
             TokenInstance {
                 token_type: Token::Fun,
                 lexeme: "fun".to_string(),
-                line: 1,
+                span: Span::new(0, 3, 1, 1),
             },
             TokenInstance {
                 token_type: Token::Identifier("addPair".to_string()),
                 lexeme: "addPair".to_string(),
-                line: 1,
+                span: Span::new(4, 7, 1, 5),
             },
             TokenInstance {
                 token_type: Token::LeftParen,
                 lexeme: "(".to_string(),
-                line: 1,
+                span: Span::new(11, 1, 1, 12),
             },
             TokenInstance {
                 token_type: Token::Identifier("a".to_string()),
                 lexeme: "a".to_string(),
-                line: 1,
+                span: Span::new(12, 1, 1, 13),
             },
             TokenInstance {
                 token_type: Token::Comma,
                 lexeme: ",".to_string(),
-                line: 1,
+                span: Span::new(13, 1, 1, 14),
             },
             TokenInstance {
                 token_type: Token::Identifier("b".to_string()),
                 lexeme: "b".to_string(),
-                line: 1,
+                span: Span::new(15, 1, 1, 16),
             },
             TokenInstance {
                 token_type: Token::RightParen,
                 lexeme: ")".to_string(),
-                line: 1,
+                span: Span::new(16, 1, 1, 17),
             },
             TokenInstance {
                 token_type: Token::LeftBrace,
                 lexeme: "{".to_string(),
-                line: 1,
+                span: Span::new(18, 1, 1, 19),
             },
             TokenInstance {
                 token_type: Token::Return,
                 lexeme: "return".to_string(),
-                line: 2,
+                span: Span::new(20, 6, 2, 1),
             },
             TokenInstance {
                 token_type: Token::Identifier("a".to_string()),
                 lexeme: "a".to_string(),
-                line: 2,
+                span: Span::new(27, 1, 2, 8),
             },
             TokenInstance {
                 token_type: Token::Plus,
                 lexeme: "+".to_string(),
-                line: 2,
+                span: Span::new(29, 1, 2, 10),
             },
             TokenInstance {
                 token_type: Token::Identifier("b".to_string()),
                 lexeme: "b".to_string(),
-                line: 2,
+                span: Span::new(31, 1, 2, 12),
             },
             TokenInstance {
                 token_type: Token::Semicolon,
                 lexeme: ";".to_string(),
-                line: 2,
+                span: Span::new(32, 1, 2, 13),
             },
             TokenInstance {
                 token_type: Token::RightBrace,
                 lexeme: "}".to_string(),
-                line: 3,
+                span: Span::new(34, 1, 3, 1),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 3,
+                span: Span::new(35, 0, 3, 2),
             },
         ];
 
-        assert_eq!(scan(&input).unwrap(), expected);
+        assert_eq!(scan(input).unwrap(), expected);
     }
 
     #[test]
@@ -710,32 +954,32 @@ mod tests {
             TokenInstance {
                 token_type: Token::Number(120.0),
                 lexeme: "120".to_string(),
-                line: 1,
+                span: Span::new(0, 3, 1, 1),
             },
             TokenInstance {
                 token_type: Token::Comma,
                 lexeme: ",".to_string(),
-                line: 1,
+                span: Span::new(3, 1, 1, 4),
             },
             TokenInstance {
                 token_type: Token::Number(120.5),
                 lexeme: "120.5".to_string(),
-                line: 1,
+                span: Span::new(4, 5, 1, 5),
             },
             TokenInstance {
                 token_type: Token::Comma,
                 lexeme: ",".to_string(),
-                line: 1,
+                span: Span::new(9, 1, 1, 10),
             },
             TokenInstance {
                 token_type: Token::Number(121.0),
                 lexeme: "121".to_string(),
-                line: 1,
+                span: Span::new(10, 3, 1, 11),
             },
             TokenInstance {
                 token_type: Token::Eof,
                 lexeme: "".to_string(),
-                line: 1,
+                span: Span::new(13, 0, 1, 14),
             },
         ];
 
@@ -756,4 +1000,188 @@ mod tests {
         assert_eq! {num_format(s4), "100.123".to_string()}
         assert_eq! {num_format(s5), "100.123".to_string()}
     }
+
+    #[test]
+    fn scan_test_reports_all_errors() {
+        let (tokens, errors) = scan_with_errors("@ 1\n# 2 \"abc");
+
+        let token_types: Vec<String> = tokens.iter().map(|token| format!("{:?}", token)).collect();
+        assert_eq!(vec!["NUMBER 1 1.0", "NUMBER 2 2.0", "EOF  null"], token_types);
+
+        let error_lines: Vec<usize> = errors.iter().map(|err| err.span().line).collect();
+        assert_eq!(vec![1, 2, 2], error_lines);
+        assert!(matches!(errors[0], ScanError::UnexpectedChar('@', _)));
+        assert!(matches!(errors[1], ScanError::UnexpectedChar('#', _)));
+        assert!(matches!(errors[2], ScanError::UnterminatedString(_)));
+
+        assert_eq!(3, scan("@ 1\n# 2 \"abc").unwrap_err().len());
+    }
+
+    #[test]
+    fn scan_test_byte_offsets() {
+        let (tokens, errors) = scan_with_errors("\"h\u{e9}llo\" \u{a3} x");
+
+        assert_eq!("STRING \"h\u{e9}llo\" h\u{e9}llo", format!("{:?}", tokens[0]));
+        assert_eq!((0, 8), (tokens[0].span.offset, tokens[0].span.len));
+        assert_eq!("IDENTIFIER x null", format!("{:?}", tokens[1]));
+        assert_eq!((12, 1), (tokens[1].span.offset, tokens[1].span.len));
+        assert_eq!((13, 0), (tokens[2].span.offset, tokens[2].span.len));
+
+        let ScanError::UnexpectedChar(c, span) = errors[0] else {
+            panic!("expected an unexpected character");
+        };
+        assert_eq!(('\u{a3}', 9, 2), (c, span.offset, span.len));
+    }
+
+    #[test]
+    fn scan_test_unicode() {
+        let source = "var caf\u{e9} = \"\u{2615} \u{1f370}\"; // \u{e7}a va \u{2713}\n\u{65e5}\u{672c} >= 1.5 + _\u{f1};\n\u{20ac} x";
+        let (tokens, errors) = scan_with_errors(source);
+
+        let tokens: Vec<(String, usize, usize, usize, usize)> = tokens
+            .iter()
+            .map(|token| {
+                let span = token.span;
+                (format!("{:?}", token), span.offset, span.len, span.line, span.column)
+            })
+            .collect();
+        let expected = vec![
+            ("VAR var null", 0, 3, 1, 1),
+            ("IDENTIFIER caf\u{e9} null", 4, 5, 1, 5),
+            ("EQUAL = null", 10, 1, 1, 10),
+            ("STRING \"\u{2615} \u{1f370}\" \u{2615} \u{1f370}", 12, 10, 1, 12),
+            ("SEMICOLON ; null", 22, 1, 1, 17),
+            ("IDENTIFIER \u{65e5}\u{672c} null", 38, 6, 2, 1),
+            ("GREATER_EQUAL >= null", 45, 2, 2, 4),
+            ("NUMBER 1.5 1.5", 48, 3, 2, 7),
+            ("PLUS + null", 52, 1, 2, 11),
+            ("IDENTIFIER _\u{f1} null", 54, 3, 2, 13),
+            ("SEMICOLON ; null", 57, 1, 2, 15),
+            ("IDENTIFIER x null", 63, 1, 3, 3),
+            ("EOF  null", 64, 0, 3, 4),
+        ];
+        let expected: Vec<(String, usize, usize, usize, usize)> = expected
+            .into_iter()
+            .map(|(token, offset, len, line, column)| (token.to_string(), offset, len, line, column))
+            .collect();
+        assert_eq!(expected, tokens);
+
+        // Symbols that aren't letters can't start an identifier
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], ScanError::UnexpectedChar('\u{20ac}', _)));
+        assert_eq!(Span::new(59, 3, 3, 1), errors[0].span());
+    }
+
+    #[test]
+    fn scan_test_escapes() {
+        let tokens = scan(r#""a\"b\\c\n\t\r\0\u{e9}\u{1F370}" r"C:\new" "" r"""#).unwrap();
+
+        let decoded: Vec<(&str, String)> = tokens
+            .iter()
+            .filter_map(|token| match &token.token_type {
+                Token::String(value) => Some((token.lexeme.as_str(), value.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (r#""a\"b\\c\n\t\r\0\u{e9}\u{1F370}""#, "a\"b\\c\n\t\r\0\u{e9}\u{1f370}".to_string()),
+                (r#"r"C:\new""#, r"C:\new".to_string()),
+                (r#""""#, "".to_string()),
+                (r#"r"""#, "".to_string()),
+            ],
+            decoded
+        );
+        assert_eq!(Span::new(33, 9, 1, 34), tokens[1].span);
+    }
+
+    #[test]
+    fn scan_test_invalid_escapes() {
+        let (tokens, errors) = scan_with_errors("\"\u{e9}\\q\" \"\\u{110000}\\x\" \"\\u{}\" \"\\u{41\" x");
+
+        // Bad strings are still tokens, with each bad escape replaced, and
+        // report their first bad escape
+        let token_types: Vec<String> = tokens.iter().map(|token| format!("{:?}", token)).collect();
+        assert_eq!(
+            vec![
+                "STRING \"\u{e9}\\q\" \u{e9}\u{fffd}",
+                "STRING \"\\u{110000}\\x\" \u{fffd}\u{fffd}",
+                "STRING \"\\u{}\" \u{fffd}",
+                "STRING \"\\u{41\" \u{fffd}",
+                "IDENTIFIER x null",
+                "EOF  null",
+            ],
+            token_types
+        );
+        let escapes: Vec<(String, Span)> = errors
+            .iter()
+            .map(|err| match err {
+                ScanError::InvalidEscape(escape, span) => (escape.clone(), *span),
+                _ => panic!("expected an invalid escape, got {:?}", err),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("\\q".to_string(), Span::new(3, 2, 1, 3)),
+                ("\\u{110000}".to_string(), Span::new(8, 10, 1, 8)),
+                ("\\u{}".to_string(), Span::new(23, 4, 1, 23)),
+                ("\\u{41".to_string(), Span::new(30, 5, 1, 30)),
+            ],
+            escapes
+        );
+        assert_eq!("Invalid escape sequence '\\q'.", errors[0].to_string());
+    }
+
+    #[test]
+    fn scan_test_interpolation() {
+        let tokens = scan("\"a ${b + \"{${c}}\"} {d} \\${e}\" r\"${f}\"").unwrap();
+
+        let token_types: Vec<String> = tokens.iter().map(|token| format!("{:?}", token)).collect();
+        assert_eq!(
+            vec![
+                "INTERPOLATION \"a ${ a ",
+                "IDENTIFIER b null",
+                "PLUS + null",
+                "INTERPOLATION \"{${ {",
+                "IDENTIFIER c null",
+                "STRING }}\" }",
+                "STRING } {d} \\${e}\"  {d} ${e}",
+                "STRING r\"${f}\" ${f}",
+                "EOF  null",
+            ],
+            token_types
+        );
+
+        // Braces in an interpolated expression don't end it
+        let (_, errors) = scan_with_errors("print \"a ${ { } \"b${c\n");
+        let errors: Vec<(String, Span)> = errors.iter().map(|err| (err.to_string(), err.span())).collect();
+        assert_eq!(
+            vec![
+                ("Unterminated string.".to_string(), Span::new(16, 4, 1, 17)),
+                ("Unterminated string.".to_string(), Span::new(6, 5, 1, 7)),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn scan_test_block_comments() {
+        let (tokens, errors) = scan_with_errors("a /* b\n/* c */\nd */ e /**/ f\n/* g /* h */\n");
+
+        let tokens: Vec<(String, usize)> = tokens
+            .iter()
+            .map(|token| (format!("{:?}", token), token.span.line))
+            .collect();
+        let expected = vec![
+            ("IDENTIFIER a null".to_string(), 1),
+            ("IDENTIFIER e null".to_string(), 3),
+            ("IDENTIFIER f null".to_string(), 3),
+            ("EOF  null".to_string(), 5),
+        ];
+        assert_eq!(expected, tokens);
+
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], ScanError::UnterminatedComment(_)));
+        assert_eq!(Span::new(29, 2, 4, 1), errors[0].span());
+    }
 }
@@ -0,0 +1,786 @@
+// Virtual machine for Lox
+// Runs the bytecode made by the compiler on a stack, following the virtual
+// machine of the book's clox. Each call gets a frame whose slots are a window
+// onto the stack starting at the function being called. Strings are interned
+// by the heap, so names are looked up and strings compared by their handles.
+use crate::chunk::OpCode;
+use crate::compile::compile;
+use crate::debug::disassemble_instruction;
+use crate::eval::{MAX_CALL_DEPTH, RuntimeError};
+use crate::interpreter::InterpreterError;
+use crate::object::{
+    BoundMethod, Class, Closure, Function, GcStats, Heap, Instance, Native, Obj, ObjRef, Upvalue,
+    Value,
+};
+use crate::parse::{self, Stmt};
+#[cfg(test)]
+use crate::scan::Span;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Calls nested deeper than the tree walking interpreter allows are a stack
+// overflow, the extra frame being the script's
+const FRAMES_MAX: usize = MAX_CALL_DEPTH + 1;
+
+struct CallFrame {
+    closure: ObjRef,
+    function: Rc<Function>,
+    ip: usize,
+    base: usize,
+}
+
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    // Keyed by the interned names of the globals
+    globals: HashMap<ObjRef, Value>,
+    init_string: ObjRef,
+    // Upvalues still pointing at the stack, ordered by slot
+    open_upvalues: Vec<ObjRef>,
+    output: Box<dyn Write>,
+    // Print the stack and each instruction before it is run
+    trace: bool,
+}
+
+type VmResult<T> = Result<T, RuntimeError>;
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    /// new creates a virtual machine that prints to stdout
+    pub fn new() -> Self {
+        Vm::with_output(Box::new(io::stdout()))
+    }
+    /// with_output creates a virtual machine whose `print` writes to `output`
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = Vm {
+            heap,
+            stack: vec![],
+            frames: vec![],
+            globals: HashMap::new(),
+            init_string,
+            open_upvalues: vec![],
+            output,
+            trace: false,
+        };
+        vm.register_native("clock", 0, |_| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|err| RuntimeError::new(format!("Clock error: {}", err)))?;
+            Ok(parse::Value::Number(now.as_secs_f64()))
+        });
+        vm
+    }
+
+    /// register_native makes a Rust closure callable from Lox as a function
+    /// called `name`, taking the same closures as `Interpreter::register_native`
+    /// so that natives work on either backend. Only nil, booleans, numbers and
+    /// strings can be passed to and returned from them.
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[parse::Value]) -> Result<parse::Value, RuntimeError> + 'static,
+    {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        // The name is kept on the stack so that it survives allocating the native
+        let name = self.intern(name.to_string());
+        self.stack.push(Value::Obj(name));
+        let native = self.alloc(Obj::Native(native));
+        self.stack.pop();
+        self.globals.insert(name, Value::Obj(native));
+    }
+
+    /// interpret compiles and runs resolved statements. Globals are kept
+    /// between calls, as the REPL needs.
+    pub fn interpret(&mut self, stmts: &[Stmt]) -> Result<(), InterpreterError> {
+        let function = compile(stmts, &mut self.heap).map_err(InterpreterError::CompileError)?;
+        let closure = self.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: vec![],
+        }));
+        self.stack.push(Value::Obj(closure));
+        let result = self.call_closure(closure, 0).and_then(|_| self.run());
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        Ok(result?)
+    }
+
+    /// set_trace turns on printing the stack and disassembling each
+    /// instruction, to the same output as `print`, before running it
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    fn trace_instruction(&mut self) -> VmResult<()> {
+        let frame = self.frames.last().expect("no frame");
+        let mut out = String::from("          ");
+        for value in &self.stack {
+            out.push_str(&format!("[ {} ]", self.heap.display(*value)));
+        }
+        out.push('\n');
+        out.push_str(&disassemble_instruction(&frame.function.chunk, frame.ip, &self.heap));
+        self.output
+            .write_all(out.as_bytes())
+            .map_err(|err| RuntimeError::new(format!("Output error: {}", err)))
+    }
+
+    /// set_gc_stress makes the heap collect garbage before every allocation
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    /// set_heap_growth_factor sets how much the heap may grow after a
+    /// collection before the next one, as a multiple of what survived it
+    pub fn set_heap_growth_factor(&mut self, growth_factor: f64) {
+        self.heap.set_growth_factor(growth_factor);
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // Every allocation while running goes through here, to collect garbage
+    // when the heap asks for it. The roots are everything the machine can
+    // reach, along with whatever the new object refers to, which may only be
+    // held on to by the caller until it is allocated.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            let mut roots = obj.references();
+            roots.extend(self.stack.iter().copied());
+            roots.push(Value::Obj(self.init_string));
+            for (name, value) in &self.globals {
+                roots.extend([Value::Obj(*name), *value]);
+            }
+            roots.extend(self.frames.iter().map(|frame| Value::Obj(frame.closure)));
+            roots.extend(self.open_upvalues.iter().map(|upvalue| Value::Obj(*upvalue)));
+            self.heap.collect(roots);
+        }
+        self.heap.alloc(obj)
+    }
+
+    fn intern(&mut self, string: String) -> ObjRef {
+        match self.heap.find_string(&string) {
+            Some(obj) => obj,
+            None => self.alloc(Obj::String(string.into())),
+        }
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no frame")
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn constant(&self, index: u8) -> Value {
+        self.frames.last().expect("no frame").function.chunk.constants[index as usize]
+    }
+
+    // Names are string constants
+    fn constant_name(&self, index: u8) -> ObjRef {
+        match self.constant(index) {
+            Value::Obj(obj) => obj,
+            _ => unreachable!("names are strings"),
+        }
+    }
+
+    fn name_text(&self, name: ObjRef) -> &str {
+        self.heap.string(name).unwrap_or_default()
+    }
+
+    // Runtime errors get the span of the instruction that raised them
+    fn error(&self, message: impl Into<String>) -> RuntimeError {
+        let mut err = RuntimeError::new(message);
+        if let Some(frame) = self.frames.last() {
+            err.span = frame.function.chunk.spans.get(frame.ip.saturating_sub(1)).copied();
+        }
+        err
+    }
+
+    fn run(&mut self) -> VmResult<()> {
+        loop {
+            if self.trace {
+                self.trace_instruction()?;
+            }
+            let frame = self.frame();
+            let op = frame.function.chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                OpCode::Constant(index) => self.stack.push(self.constant(index)),
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal(slot) => {
+                    let base = self.frame().base;
+                    self.stack.push(self.stack[base + slot as usize]);
+                }
+                OpCode::SetLocal(slot) => {
+                    let base = self.frame().base;
+                    self.stack[base + slot as usize] = self.peek(0);
+                }
+                OpCode::GetGlobal(index) => {
+                    let name = self.constant_name(index);
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(self.error(format!("Undefined variable '{}'.", self.name_text(name)))),
+                    }
+                }
+                OpCode::DefineGlobal(index) => {
+                    let name = self.constant_name(index);
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal(index) => {
+                    let name = self.constant_name(index);
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.error(format!("Undefined variable '{}'.", self.name_text(name)))),
+                    }
+                }
+                OpCode::GetUpvalue(index) => {
+                    let upvalue = self.upvalue(index);
+                    let value = match self.heap.get(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => Value::Nil,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let upvalue = self.upvalue(index);
+                    let value = self.peek(0);
+                    match self.heap.get_mut(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Obj::Upvalue(closed) => *closed = Upvalue::Closed(value),
+                        _ => (),
+                    }
+                }
+                OpCode::GetProperty(index) => {
+                    let name = self.constant_name(index);
+                    let Some(instance) = self.instance(self.peek(0)) else {
+                        return Err(self.error("Only instances have properties."));
+                    };
+                    let (class, field) = match self.heap.get(instance) {
+                        Obj::Instance(instance) => {
+                            (instance.class, instance.fields.get(&name).copied())
+                        }
+                        _ => unreachable!(),
+                    };
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.stack.push(value);
+                        }
+                        None => self.bind_method(class, name)?,
+                    }
+                }
+                OpCode::SetProperty(index) => {
+                    let name = self.constant_name(index);
+                    let Some(instance) = self.instance(self.peek(1)) else {
+                        return Err(self.error("Only instances have fields."));
+                    };
+                    let value = self.pop();
+                    if let Obj::Instance(instance) = self.heap.get_mut(instance) {
+                        instance.fields.insert(name, value);
+                    }
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper(index) => {
+                    let name = self.constant_name(index);
+                    let superclass = match self.pop() {
+                        Value::Obj(superclass) => superclass,
+                        _ => return Err(self.error("Superclass must be a class.")),
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(Value::Boolean(left == right));
+                }
+                OpCode::Greater => self.comparison(|a, b| a > b)?,
+                OpCode::GreaterEqual => self.comparison(|a, b| a >= b)?,
+                OpCode::Less => self.comparison(|a, b| a < b)?,
+                OpCode::LessEqual => self.comparison(|a, b| a <= b)?,
+                OpCode::Add => match (self.peek(1), self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.stack.truncate(self.stack.len() - 2);
+                        self.stack.push(Value::Number(a + b));
+                    }
+                    (Value::Obj(a), Value::Obj(b)) => {
+                        let (Some(a), Some(b)) = (self.heap.string(a), self.heap.string(b)) else {
+                            return Err(self.error("Operands must be two numbers or two strings."));
+                        };
+                        let string = self.intern(format!("{}{}", a, b));
+                        self.stack.truncate(self.stack.len() - 2);
+                        self.stack.push(Value::Obj(string));
+                    }
+                    _ => return Err(self.error("Operands must be two numbers or two strings.")),
+                },
+                OpCode::Subtract => self.arithmetic(|a, b| a - b)?,
+                OpCode::Multiply => self.arithmetic(|a, b| a * b)?,
+                OpCode::Divide => self.arithmetic(|a, b| a / b)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(is_falsey(value)));
+                }
+                OpCode::Negate => match self.peek(0) {
+                    Value::Number(n) => {
+                        self.pop();
+                        self.stack.push(Value::Number(-n));
+                    }
+                    _ => return Err(self.error("Operand must be a number.")),
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    let text = self.heap.display(value).to_string();
+                    writeln!(self.output, "{}", text)
+                        .map_err(|err| self.error(format!("Output error: {}", err)))?;
+                }
+                // The parts stay on the stack, where the collector can see
+                // them, until the string is made
+                OpCode::Interpolate(count) => {
+                    let start = self.stack.len() - count as usize;
+                    let string: String = self.stack[start..]
+                        .iter()
+                        .map(|value| self.heap.display(*value).to_string())
+                        .collect();
+                    let string = self.intern(string);
+                    self.stack.truncate(start);
+                    self.stack.push(Value::Obj(string));
+                }
+                OpCode::Jump(distance) => self.frame().ip += distance as usize,
+                OpCode::JumpIfFalse(distance) => {
+                    if is_falsey(self.peek(0)) {
+                        self.frame().ip += distance as usize;
+                    }
+                }
+                OpCode::Loop(distance) => self.frame().ip -= distance as usize,
+                OpCode::Call(arg_count) => self.call_value(self.peek(arg_count as usize), arg_count)?,
+                OpCode::Closure(index) => {
+                    let function = match self.constant(index) {
+                        Value::Obj(obj) => match self.heap.get(obj) {
+                            Obj::Function(function) => Rc::clone(function),
+                            _ => unreachable!(),
+                        },
+                        _ => unreachable!(),
+                    };
+                    let base = self.frame().base;
+                    let upvalues = function
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| match upvalue.is_local {
+                            true => self.capture_upvalue(base + upvalue.index as usize),
+                            false => self.upvalue(upvalue.index),
+                        })
+                        .collect();
+                    let closure = self.alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("no frame");
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::Class(index) => {
+                    let class = Class {
+                        name: self.name_text(self.constant_name(index)).to_string(),
+                        methods: HashMap::new(),
+                    };
+                    let class = self.alloc(Obj::Class(class));
+                    self.stack.push(Value::Obj(class));
+                }
+                OpCode::Inherit => {
+                    let methods = match self.peek(1) {
+                        Value::Obj(obj) => match self.heap.get(obj) {
+                            Obj::Class(superclass) => superclass.methods.clone(),
+                            _ => return Err(self.error("Superclass must be a class.")),
+                        },
+                        _ => return Err(self.error("Superclass must be a class.")),
+                    };
+                    if let Value::Obj(subclass) = self.peek(0)
+                        && let Obj::Class(subclass) = self.heap.get_mut(subclass)
+                    {
+                        subclass.methods.extend(methods);
+                    }
+                    self.pop();
+                }
+                OpCode::Method(index) => {
+                    let name = self.constant_name(index);
+                    if let (Value::Obj(method), Value::Obj(class)) = (self.peek(0), self.peek(1))
+                        && let Obj::Class(class) = self.heap.get_mut(class)
+                    {
+                        class.methods.insert(name, method);
+                    }
+                    self.pop();
+                }
+            }
+        }
+    }
+
+    fn upvalue(&mut self, index: u8) -> ObjRef {
+        let closure = self.frame().closure;
+        match self.heap.get(closure) {
+            Obj::Closure(closure) => closure.upvalues[index as usize],
+            _ => unreachable!(),
+        }
+    }
+
+    fn instance(&self, value: Value) -> Option<ObjRef> {
+        match value {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    // Replace the instance on top of the stack with its method `name`
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> VmResult<()> {
+        let method = match self.heap.get(class) {
+            Obj::Class(class) => class.methods.get(&name).copied(),
+            _ => None,
+        };
+        let Some(method) = method else {
+            return Err(self.error(format!("Undefined property '{}'.", self.name_text(name))));
+        };
+        let receiver = self.pop();
+        let bound = self.alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.stack.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, f: fn(f64, f64) -> f64) -> VmResult<()> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(Value::Number(f(a, b)));
+                Ok(())
+            }
+            _ => Err(self.error("Operands must be numbers.")),
+        }
+    }
+
+    fn comparison(&mut self, f: fn(f64, f64) -> bool) -> VmResult<()> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(Value::Boolean(f(a, b)));
+                Ok(())
+            }
+            _ => Err(self.error("Operands must be numbers.")),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> VmResult<()> {
+        let Value::Obj(obj) = callee else {
+            return Err(self.error("Can only call functions and classes."));
+        };
+        let callee_slot = self.stack.len() - 1 - arg_count as usize;
+        match self.heap.get(obj) {
+            Obj::Closure(_) => self.call_closure(obj, arg_count),
+            Obj::BoundMethod(bound) => {
+                let method = bound.method;
+                self.stack[callee_slot] = bound.receiver;
+                self.call_closure(method, arg_count)
+            }
+            Obj::Class(class) => {
+                let initializer = class.methods.get(&self.init_string).copied();
+                let instance = Instance {
+                    class: obj,
+                    fields: HashMap::new(),
+                };
+                self.stack[callee_slot] = Value::Obj(self.alloc(Obj::Instance(instance)));
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.error(format!(
+                        "Expected 0 arguments but got {}.",
+                        arg_count
+                    ))),
+                    None => Ok(()),
+                }
+            }
+            Obj::Native(native) => {
+                if arg_count as usize != native.arity {
+                    return Err(self.error(format!(
+                        "Expected {} arguments but got {}.",
+                        native.arity, arg_count
+                    )));
+                }
+                let arguments = self.stack[callee_slot + 1..]
+                    .iter()
+                    .map(|argument| self.native_argument(*argument))
+                    .collect::<VmResult<Vec<parse::Value>>>()?;
+                let result = (native.function)(&arguments).map_err(|err| self.error(err.message))?;
+                let result = self.native_result(result)?;
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err(self.error("Can only call functions and classes.")),
+        }
+    }
+
+    // Natives are handed values the tree walking interpreter can hold, which
+    // have no way to refer to objects on this heap other than strings
+    fn native_argument(&self, value: Value) -> VmResult<parse::Value> {
+        match value {
+            Value::Nil => Ok(parse::Value::Nil),
+            Value::Boolean(b) => Ok(parse::Value::Boolean(b)),
+            Value::Number(n) => Ok(parse::Value::Number(n)),
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Obj::String(string) => Ok(parse::Value::String(Rc::clone(string))),
+                _ => Err(self.error("Native functions only take nil, booleans, numbers and strings.")),
+            },
+        }
+    }
+
+    fn native_result(&mut self, value: parse::Value) -> VmResult<Value> {
+        match value {
+            parse::Value::Nil => Ok(Value::Nil),
+            parse::Value::Boolean(b) => Ok(Value::Boolean(b)),
+            parse::Value::Number(n) => Ok(Value::Number(n)),
+            parse::Value::String(string) => Ok(Value::Obj(self.intern(string.to_string()))),
+            _ => Err(self.error("Native functions only return nil, booleans, numbers and strings.")),
+        }
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, arg_count: u8) -> VmResult<()> {
+        let function = match self.heap.get(closure) {
+            Obj::Closure(closure) => Rc::clone(&closure.function),
+            _ => unreachable!(),
+        };
+        if arg_count as usize != function.arity {
+            return Err(self.error(format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            )));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.error("Stack overflow."));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            base: self.stack.len() - 1 - arg_count as usize,
+        });
+        Ok(())
+    }
+
+    // Closures capturing the same variable share one upvalue
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.iter().position(|upvalue| {
+            matches!(self.heap.get(*upvalue), Obj::Upvalue(Upvalue::Open(open)) if *open >= slot)
+        });
+        if let Some(position) = position
+            && let Obj::Upvalue(Upvalue::Open(open)) = self.heap.get(self.open_upvalues[position])
+            && *open == slot
+        {
+            return self.open_upvalues[position];
+        }
+        let upvalue = self.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        let position = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    // Move the values of variables in slots from `last` up off the stack and
+    // into the upvalues capturing them
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let Obj::Upvalue(Upvalue::Open(slot)) = self.heap.get(upvalue) else {
+                break;
+            };
+            if *slot < last {
+                break;
+            }
+            let value = self.stack[*slot];
+            *self.heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Closed(value));
+            self.open_upvalues.pop();
+        }
+    }
+}
+
+// Like the tree walking interpreter, only nil and false are false
+fn is_falsey(value: Value) -> bool {
+    matches!(value, Value::Nil | Value::Boolean(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::OutputBuffer;
+    use crate::interpreter::Interpreter;
+    use std::cell::Cell;
+
+    fn run(vm: &mut Vm, source: &str) -> Result<(), InterpreterError> {
+        vm.interpret(&Interpreter::compile(source).unwrap())
+    }
+
+    #[test]
+    fn test_closures_and_classes() {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        let source = "
+fun makeCounter() {
+  var i = 0;
+  fun count() { i = i + 1; return i; }
+  return count;
+}
+var counter = makeCounter();
+counter();
+print counter();
+
+class A { name() { return \"A\"; } }
+class B < A {
+  init(n) { this.n = n; }
+  name() { return super.name() + \"B\" + this.n; }
+}
+print B(\"1\").name();
+print B;
+print B(\"2\");
+";
+        run(&mut vm, source).unwrap();
+        assert_eq!("2\nAB1\nB\nB instance\n", output.contents());
+    }
+
+    #[test]
+    fn test_register_native() {
+        // The same closure works on both backends
+        fn greet(args: &[parse::Value]) -> Result<parse::Value, RuntimeError> {
+            match &args[0] {
+                parse::Value::String(name) => Ok(parse::Value::String(format!("hi {}", name).into())),
+                _ => Err(RuntimeError::new("Expected a name.")),
+            }
+        }
+        let source = "print greet(\"lox\"); print greet(\"lo\" + \"x\") == \"hi lox\";";
+        let output = OutputBuffer::new();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.register_native("greet", 1, greet);
+        run(&mut vm, source).unwrap();
+        let tree_output = OutputBuffer::new();
+        let mut interpreter = Interpreter::with_output(Box::new(tree_output.clone()));
+        interpreter.register_native("greet", 1, greet);
+        interpreter.run_source(source).unwrap();
+        assert_eq!("hi lox\ntrue\n", output.contents());
+        assert_eq!(tree_output.contents(), output.contents());
+
+        let count = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&count);
+        vm.register_native("tick", 0, move |_| {
+            counter.set(counter.get() + 1);
+            Ok(parse::Value::Nil)
+        });
+        run(&mut vm, "tick(); tick();").unwrap();
+        assert_eq!(2, count.get());
+        let Err(InterpreterError::RuntimeError(err)) = run(&mut vm, "greet(greet);") else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!("Native functions only take nil, booleans, numbers and strings.", err.message);
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let mut vm = Vm::with_output(Box::new(OutputBuffer::new()));
+        let Err(InterpreterError::RuntimeError(err)) = run(&mut vm, "var a = 1;\nprint a + nil;") else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!("Operands must be two numbers or two strings.", err.message);
+        assert_eq!(Some(Span::new(17, 7, 2, 7)), err.span());
+
+        let Err(InterpreterError::RuntimeError(err)) = run(&mut vm, "fun f() { f(); }\nf();") else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!("Stack overflow.", err.message);
+
+        // The machine is left ready to run more code, with its globals intact
+        assert!(run(&mut vm, "print a;").is_ok());
+    }
+
+    #[test]
+    fn test_trace() {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_trace(true);
+        run(&mut vm, "print 1 + 2;").unwrap();
+        let expected = "          [ <script> ]
+0000    1 OP_CONSTANT         0 '1'
+          [ <script> ][ 1 ]
+0001    | OP_CONSTANT         1 '2'
+          [ <script> ][ 1 ][ 2 ]
+0002    | OP_ADD
+          [ <script> ][ 3 ]
+0003    | OP_PRINT
+3
+          [ <script> ]
+0004    | OP_NIL
+          [ <script> ][ nil ]
+0005    | OP_RETURN
+";
+        assert_eq!(expected, output.contents());
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_gc_stress(true);
+        let source = "
+class Node {
+  init(value, next) { this.value = value; this.next = next; }
+}
+fun makeAdder(n) { fun add(x) { return x + n; } return add; }
+var list = nil;
+for (var i = 0; i < 10; i = i + 1) {
+  var add = makeAdder(\"+\");
+  list = Node(add(\"n\"), list);
+}
+var total = \"\";
+while (list != nil) { total = total + list.value; list = list.next; }
+print total;
+";
+        run(&mut vm, source).unwrap();
+        assert_eq!("n+n+n+n+n+n+n+n+n+n+\n", output.contents());
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 10);
+        assert!(stats.bytes_freed > 0);
+        assert!(stats.bytes_freed < stats.bytes_allocated);
+    }
+}
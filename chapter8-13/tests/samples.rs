@@ -0,0 +1,158 @@
+// Golden tests for Lox
+// Runs every script in samples/ through the rlox binary and checks what it
+// prints against the annotations in the script, in the same way as the book's
+// test suite:
+//
+//   print 1 + 2;    // expect: 3
+//   print nil + 1;  // expect runtime error: Operands must be ...
+//   var a = a;      // Error at 'a': Can't read local variable ...
+//   // [line 3] Error at end: Expect '}' after block.
+//
+// A script is run with no arguments unless it starts with an `// args: ...`
+// comment, which is how the scanner samples ask for their tokens to be printed.
+// Every script is run by both the tree walking interpreter and the virtual
+// machine. Adding a script to samples/ is all it takes to add a test.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const ARGS: &str = "// args: ";
+
+/// What running a sample should produce
+#[derive(Debug, Default, PartialEq)]
+struct Expected {
+    args: Vec<String>,
+    output: Vec<String>,
+    errors: Vec<String>,
+    exit_code: i32,
+}
+
+fn parse_expectations(source: &str) -> Expected {
+    let mut expected = Expected::default();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        if index == 0
+            && let Some(args) = line.strip_prefix(ARGS)
+        {
+            expected.args = args.split_whitespace().map(String::from).collect();
+        }
+        let Some(comment) = line.find("//").map(|start| &line[start..]) else {
+            continue;
+        };
+        if let Some(output) = comment.strip_prefix(EXPECT) {
+            expected.output.push(output.to_string());
+        } else if let Some(message) = comment.strip_prefix(EXPECT_RUNTIME_ERROR) {
+            expected.errors.push(message.to_string());
+            expected.errors.push(format!("[line {}]", line_number));
+            expected.exit_code = 70;
+        } else if let Some(error) = comment.strip_prefix("// Error") {
+            expected
+                .errors
+                .push(format!("[line {}] Error{}", line_number, error));
+            expected.exit_code = 65;
+        } else if let Some(error) = comment.strip_prefix("// [line ") {
+            expected.errors.push(format!("[line {}", error));
+            expected.exit_code = 65;
+        }
+    }
+    expected
+}
+
+/// Run the sample at `path` with `backend` and describe how it differs from
+/// its annotations
+fn check_sample(path: &Path, backend: &str) -> Option<String> {
+    let source = fs::read_to_string(path).unwrap();
+    let expected = parse_expectations(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(&expected.args)
+        .args(["--error-format", "book", "--backend", backend])
+        .arg(path)
+        .output()
+        .unwrap();
+
+    let lines = |bytes: &[u8]| -> Vec<String> {
+        String::from_utf8_lossy(bytes)
+            .lines()
+            .map(String::from)
+            .collect()
+    };
+    let actual = Expected {
+        args: expected.args.clone(),
+        output: lines(&output.stdout),
+        errors: lines(&output.stderr),
+        exit_code: output.status.code().unwrap_or(-1),
+    };
+    if actual == expected {
+        None
+    } else {
+        Some(format!(
+            "{} ({})\n  expected {:?}\n  got      {:?}",
+            path.display(),
+            backend,
+            expected,
+            actual
+        ))
+    }
+}
+
+fn samples() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("samples");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn check_samples(backend: &str) {
+    let paths = samples();
+    assert!(!paths.is_empty(), "no samples found");
+    let failures: Vec<String> = paths
+        .iter()
+        .filter_map(|path| check_sample(path, backend))
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} samples failed:\n{}",
+        failures.len(),
+        paths.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_samples_tree() {
+    check_samples("tree");
+}
+
+#[test]
+fn test_samples_vm() {
+    check_samples("vm");
+}
+
+#[test]
+fn test_parse_expectations() {
+    let source = "\
+// args: -s
+print 1; // expect: 1
+var a = a; // Error at 'a': Oops.
+// [line 4] Error at end: Oops.
+nil + 1; // expect runtime error: Oops.
+";
+    let expected = Expected {
+        args: vec!["-s".to_string()],
+        output: vec!["1".to_string()],
+        errors: vec![
+            "[line 3] Error at 'a': Oops.".to_string(),
+            "[line 4] Error at end: Oops.".to_string(),
+            "Oops.".to_string(),
+            "[line 5]".to_string(),
+        ],
+        exit_code: 70,
+    };
+    assert_eq!(expected, parse_expectations(source));
+}